@@ -0,0 +1,141 @@
+use email::{Address, Header, Mailbox};
+use error::SubmitError;
+use transport::bare_address;
+
+/// The addresses in `value`, which may be a single address or a list of
+/// them as in a `To` or `Cc` header.  Each is spelled the same way,
+/// `Name <address>` or just `address`, wherever it comes from.
+pub fn normalize(value: &str) -> Vec<String> {
+    let mut addresses = parse(value).unwrap_or(Vec::new());
+    // Leave what can't be parsed to whoever sends the mail.
+    if addresses.is_empty() && !value.trim().is_empty() {
+        addresses.push(value.trim().to_owned());
+    }
+    addresses
+}
+
+/// Like `normalize`, but a value that can't be parsed is an error.
+pub fn parse(value: &str) -> Result<Vec<String>, SubmitError> {
+    parse_header(&Header::new(String::from("To"), String::from(value)))
+}
+
+/// The addresses in a `From`, `To` or `Cc` header of a message, see
+/// `parse`.
+pub fn parse_header(header: &Header) -> Result<Vec<String>, SubmitError> {
+    let mut addresses = Vec::new();
+    for addr in try!(header.get_value::<Vec<Address>>()) {
+        match addr {
+            Address::Mailbox(mb) => addresses.push(format(mb)),
+            Address::Group(_, group) => addresses.extend(group.into_iter().map(format)),
+        }
+    }
+    Ok(addresses)
+}
+
+/// How a mailbox is spelled, its name quoted if it has to be.
+pub fn format(mb: Mailbox) -> String {
+    let name = mb.name.map_or(String::new(),
+                              |n| n.split_whitespace().collect::<Vec<_>>().join(" "));
+    if name.is_empty() || name == mb.address {
+        mb.address
+    } else if name.chars().any(|c| ",;:<>@\"()[]\\".contains(c)) {
+        format!("\"{}\" <{}>", name.replace("\\", "\\\\").replace("\"", "\\\""), mb.address)
+    } else {
+        format!("{} <{}>", name, mb.address)
+    }
+}
+
+/// What addresses are compared by: the bare address, in lower case.
+pub fn key(addr: &str) -> String {
+    bare_address(addr).to_lowercase()
+}
+
+/// Normalise the To and Cc lists and remove the duplicates, within and
+/// across them.  An address that is in both stays in To, and when it is
+/// given both with and without a name, the one with the name is kept.
+/// The addresses in `suppressed` are dropped.
+pub fn dedup(to: Vec<String>, cc: Vec<String>, suppressed: &[String])
+             -> (Vec<String>, Vec<String>) {
+    let suppressed = suppressed.iter()
+        .flat_map(|s| normalize(s))
+        .map(|s| key(s.as_str()))
+        .collect::<Vec<_>>();
+    let mut lists = vec!(Vec::new(), Vec::new());
+    // The key of every address that was kept, and where it was put.
+    let mut kept: Vec<(String, usize, usize)> = Vec::new();
+    for (i, list) in vec!(to, cc).into_iter().enumerate() {
+        for addr in list.iter().flat_map(|a| normalize(a)) {
+            let k = key(addr.as_str());
+            if suppressed.contains(&k) {
+                continue;
+            }
+            match kept.iter().find(|e| e.0 == k) {
+                Some(&(_, j, pos)) => {
+                    if !lists[j][pos].contains('<') && addr.contains('<') {
+                        lists[j][pos] = addr;
+                    }
+                    continue;
+                },
+                None => (),
+            }
+            kept.push((k, i, lists[i].len()));
+            lists[i].push(addr);
+        }
+    }
+    let cc = lists.pop().unwrap();
+    let to = lists.pop().unwrap();
+    (to, cc)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{dedup, key, normalize, parse};
+
+    use error::SubmitError;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("  Jeff   King <peff@peff.net> "),
+                   vec!(String::from("Jeff King <peff@peff.net>")));
+        assert_eq!(normalize("<list@example.com>"), vec!(String::from("list@example.com")));
+        assert_eq!(normalize("\"King, Jeff\" <peff@peff.net>, list@example.com"),
+                   vec!(String::from("\"King, Jeff\" <peff@peff.net>"),
+                        String::from("list@example.com")));
+        assert_eq!(normalize(""), Vec::<String>::new());
+        assert_eq!(normalize("@@@"), vec!(String::from("@@@")));
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("Test Name <test@example.com>").unwrap(),
+                   vec!(String::from("Test Name <test@example.com>")));
+        assert_eq!(parse("test@example.com").unwrap(), vec!(String::from("test@example.com")));
+        assert_eq!(parse("\"King, Jeff\" <peff@peff.net>").unwrap(),
+                   vec!(String::from("\"King, Jeff\" <peff@peff.net>")));
+        match parse("@@@") {
+            Err(SubmitError::Parse(_)) => (),
+            _ => panic!("the address should not parse"),
+        }
+    }
+
+    #[test]
+    fn test_key() {
+        assert_eq!(key("Jeff King <Peff@Peff.net>"), "peff@peff.net");
+        assert_eq!(key("list@EXAMPLE.com"), "list@example.com");
+    }
+
+    #[test]
+    fn test_dedup() {
+        let to = vec!(String::from("list@example.com"), String::from("peff@peff.net"),
+                      String::from("Me <me@example.com>"));
+        let cc = vec!(String::from("Jeff King <PEFF@peff.net>"),
+                      String::from("List <LIST@example.com>, bot@example.com"),
+                      String::from("Junio C Hamano <gitster@pobox.com>"),
+                      String::from("gitster@pobox.com"));
+        let (to, cc) = dedup(to, cc, &[String::from("Bot <bot@example.com>"),
+                                       String::from("me@example.com")]);
+        assert_eq!(to, vec!(String::from("List <LIST@example.com>"),
+                            String::from("Jeff King <PEFF@peff.net>")));
+        assert_eq!(cc, vec!(String::from("Junio C Hamano <gitster@pobox.com>")));
+    }
+}
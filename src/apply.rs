@@ -0,0 +1,1140 @@
+use error::SubmitError;
+use format::{self, MONTHS};
+use git2::{Commit, Error, Index, IndexEntry, IndexTime, ObjectType, Oid, Repository, ResetType,
+           Signature, Time, Tree};
+use git2::build::CheckoutBuilder;
+use rustc_serialize::base64::FromBase64;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::os::raw::{c_int, c_ulong};
+use std::path::Path;
+use std::str;
+
+/// The digits of the base 85 encoding of binary patches.
+const BASE85: &'static [u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz\
+                                !#$%&()*+-;<=>?@^_`{|}~";
+
+// zlib's one-shot inflate, which libgit2 links in already.
+extern "C" {
+    fn uncompress(dest: *mut u8, dest_len: *mut c_ulong, source: *const u8, source_len: c_ulong)
+                  -> c_int;
+}
+
+/// A patch mail, as it was formatted or as it was left after editing.
+/// libgit2 can't parse or apply patches yet, so this does what
+/// `git mailinfo` and `git apply` would.
+#[derive(Debug, PartialEq)]
+pub struct MailPatch {
+    pub name: String,
+    pub email: String,
+    pub seconds: i64,
+    pub offset: i32,
+    pub message: String,
+    files: Vec<FilePatch>,
+}
+
+#[derive(Debug, PartialEq)]
+struct FilePatch {
+    old_path: Option<String>,
+    new_path: Option<String>,
+    old_mode: Option<u32>,
+    new_mode: Option<u32>,
+    /// The (abbreviated) blob the patch was made against, for the 3-way
+    /// fallback.
+    old_id: Option<String>,
+    /// The blob the patch results in, which the result of a binary patch
+    /// is checked against.
+    new_id: Option<String>,
+    binary: bool,
+    /// The data of a `GIT binary patch`, which a patch that only says
+    /// the files differ doesn't have.
+    binary_data: Option<BinaryHunk>,
+    hunks: Vec<Hunk>,
+}
+
+/// The forward half of a binary patch, still compressed.  It is either
+/// the whole new content, or a delta against the old one.
+#[derive(Debug, PartialEq)]
+struct BinaryHunk {
+    delta: bool,
+    size: usize,
+    data: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq)]
+struct Hunk {
+    header: String,
+    old_start: usize,
+    old: Vec<Vec<u8>>,
+    new: Vec<Vec<u8>>,
+}
+
+/// Why a patch doesn't apply to a tree.
+enum Failure {
+    Hunk(String, usize, String),
+    Missing(String),
+    Exists(String),
+    NoPreimage(String),
+    Binary(String),
+    Git(Error),
+}
+
+impl From<Error> for Failure {
+    fn from(e: Error) -> Failure {
+        Failure::Git(e)
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Failure::Hunk(ref path, number, ref header) =>
+                write!(f, "hunk #{} ({}) of {} does not apply", number, header, path),
+            Failure::Missing(ref path) => write!(f, "{} does not exist", path),
+            Failure::Exists(ref path) => write!(f, "{} already exists", path),
+            Failure::NoPreimage(ref path) =>
+                write!(f, "the blob {} was patched from is not in the repository", path),
+            Failure::Binary(ref path) =>
+                write!(f, "the binary patch of {} does not apply, or does not result in the \
+                           blob it names", path),
+            Failure::Git(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Apply the patch mail at `path` on top of HEAD and commit it with the
+/// author, date and message from the mail.  If it doesn't apply, a 3-way
+/// merge with the blobs the patch was made against is tried, like
+/// `git am --3way` does.  Conflicts are left in the index and working
+/// tree, see `commit_resolved`.
+pub fn apply_mail(repo: &Repository, path: &Path) -> Result<Oid, SubmitError> {
+    let patch = try!(read(path));
+    let head = try!(head_commit(repo));
+    let head_tree = try!(head.tree());
+    match apply_to_tree(repo, &head_tree, &patch) {
+        Ok(tree) => return commit(repo, &patch, tree, true),
+        Err(Failure::Git(e)) => return Err(SubmitError::from(e)),
+        Err(failure) => println!("error: {}: {}", path.display(), failure),
+    }
+
+    println!("Falling back to a 3-way merge...");
+    match three_way(repo, &head, &patch) {
+        Ok(Ok(tree)) => commit(repo, &patch, tree, true),
+        Ok(Err(mut merged)) => {
+            for conflict in try!(write_conflicts(repo, &mut merged)) {
+                println!("CONFLICT (content): Merge conflict in {}", conflict);
+            }
+            Err(SubmitError::ApplyPatch(path.to_string_lossy().into_owned()))
+        },
+        Err(Failure::Git(e)) => Err(SubmitError::from(e)),
+        Err(failure) => {
+            println!("error: {}", failure);
+            Err(SubmitError::ApplyPatch(path.to_string_lossy().into_owned()))
+        },
+    }
+}
+
+/// Commit the index, once the conflicts of the patch at `path` have been
+/// resolved, with the author, date and message from the mail.  Like
+/// `git am --continue`, an index without any changes is refused, so a
+/// patch that failed for another reason isn't recorded as an empty
+/// commit.
+pub fn commit_resolved(repo: &Repository, path: &Path) -> Result<Oid, SubmitError> {
+    let patch = try!(read(path));
+    let mut index = try!(repo.index());
+    if index.iter().any(|e| stage(&e) != 0) {
+        println!("error: there are unresolved conflicts, \"git add\" the files once they are \
+                  resolved");
+        return Err(SubmitError::ApplyPatch(path.to_string_lossy().into_owned()));
+    }
+    let tree = try!(index.write_tree());
+    if tree == try!(head_commit(repo)).tree_id() {
+        println!("No changes - did you forget to use \"git add\"?  Apply the changes of {} \
+                  by hand, or run \"git submit abort\".", path.display());
+        return Err(SubmitError::ApplyPatch(path.to_string_lossy().into_owned()));
+    }
+    commit(repo, &patch, tree, false)
+}
+
+fn read(path: &Path) -> Result<MailPatch, SubmitError> {
+    let mut contents = Vec::new();
+    try!(try!(File::open(path)).read_to_end(&mut contents));
+    parse(&contents).map_err(|msg| {
+        println!("error: {}: {}", path.display(), msg);
+        SubmitError::ApplyPatch(path.to_string_lossy().into_owned())
+    })
+}
+
+fn head_commit(repo: &Repository) -> Result<Commit, Error> {
+    let oid = try!(try!(repo.head()).peel(ObjectType::Commit)).id();
+    repo.find_commit(oid)
+}
+
+fn commit(repo: &Repository, patch: &MailPatch, tree: Oid, checkout: bool)
+          -> Result<Oid, SubmitError> {
+    let head = try!(head_commit(repo));
+    let author = try!(Signature::new(patch.name.as_str(), patch.email.as_str(),
+                                     &Time::new(patch.seconds, patch.offset)));
+    let committer = try!(repo.signature());
+    let tree = try!(repo.find_tree(tree));
+    let oid = try!(repo.commit(Some("HEAD"), &author, &committer, patch.message.as_str(), &tree,
+                               &[&head]));
+    if checkout {
+        let obj = try!(repo.find_object(oid, None));
+        try!(repo.reset(&obj, ResetType::Hard, Some(&mut CheckoutBuilder::new())));
+    }
+    Ok(oid)
+}
+
+/// Apply the patch to `tree`, and write the resulting tree.
+fn apply_to_tree(repo: &Repository, tree: &Tree, patch: &MailPatch) -> Result<Oid, Failure> {
+    let mut index = try!(Index::new());
+    try!(index.read_tree(tree));
+    for file in patch.files.iter() {
+        let (content, mode, old_id) = match file.old_path {
+            Some(ref old_path) => {
+                let entry = match tree.get_path(Path::new(old_path)) {
+                    Ok(entry) => entry,
+                    Err(_) => return Err(Failure::Missing(old_path.clone())),
+                };
+                let blob = try!(repo.find_blob(entry.id()));
+                (blob.content().to_vec(), entry.filemode() as u32, Some(entry.id()))
+            },
+            None => {
+                if let Some(ref new_path) = file.new_path {
+                    if tree.get_path(Path::new(new_path)).is_ok() {
+                        return Err(Failure::Exists(new_path.clone()));
+                    }
+                }
+                (Vec::new(), 0o100644, None)
+            },
+        };
+        let path = file.new_path.as_ref().or(file.old_path.as_ref()).unwrap();
+        let content = if file.binary {
+            try!(binary_content(repo, file, &content, old_id, path))
+        } else {
+            try!(apply_hunks(&content, &file.hunks).map_err(|n| {
+                Failure::Hunk(path.clone(), n + 1, file.hunks[n].header.clone())
+            }))
+        };
+        if let Some(ref old_path) = file.old_path {
+            try!(index.remove(Path::new(old_path), 0));
+        }
+        if let Some(ref new_path) = file.new_path {
+            let oid = try!(repo.blob(&content));
+            try!(index.add(&entry(new_path, oid, file.new_mode.unwrap_or(mode))));
+        } else if !content.is_empty() {
+            // The file is deleted, but the patch doesn't remove all of it.
+            return Err(Failure::Hunk(path.clone(), file.hunks.len(),
+                                     file.hunks.last().map_or(String::new(),
+                                                              |h| h.header.clone())));
+        }
+    }
+    Ok(try!(index.write_tree_to(repo)))
+}
+
+/// The content of a file after a binary patch, which has to be made
+/// against `old`, the blob `old_id`.  Like `git apply`, the data in the
+/// patch is applied and has to result in the blob the patch names, so
+/// data that was edited without updating the index line is refused.  A
+/// patch without data, that only says the files differ, takes that blob
+/// from the repository.
+fn binary_content(repo: &Repository, file: &FilePatch, old: &[u8], old_id: Option<Oid>,
+                  path: &str) -> Result<Vec<u8>, Failure> {
+    let preimage = match (file.old_id.as_ref(), old_id) {
+        (Some(id), Some(oid)) => oid.to_string().starts_with(id.as_str()),
+        (None, None) => true,
+        _ => false,
+    };
+    if !preimage {
+        return Err(Failure::Binary(path.to_owned()));
+    }
+    let hunk = match (file.binary_data.as_ref(), file.new_id.as_ref()) {
+        (Some(hunk), _) => hunk,
+        (None, Some(new_id)) => {
+            return match repo.revparse_single(new_id.as_str())
+                .and_then(|o| o.peel(ObjectType::Blob)) {
+                Ok(blob) => Ok(try!(repo.find_blob(blob.id())).content().to_vec()),
+                Err(_) => Err(Failure::Binary(path.to_owned())),
+            };
+        },
+        (None, None) => return Ok(Vec::new()),
+    };
+    let data = inflate(&hunk.data, hunk.size);
+    let content = if hunk.delta { data.and_then(|d| apply_delta(old, &d)) } else { data };
+    let content = match content {
+        Some(content) => content,
+        None => return Err(Failure::Binary(path.to_owned())),
+    };
+    let matches = match file.new_id {
+        Some(ref new_id) => try!(repo.blob(&content)).to_string().starts_with(new_id.as_str()),
+        None => content.is_empty(),
+    };
+    if !matches {
+        return Err(Failure::Binary(path.to_owned()));
+    }
+    Ok(content)
+}
+
+/// Inflate the data of a binary patch, which has to come to `size`
+/// bytes.
+fn inflate(data: &[u8], size: usize) -> Option<Vec<u8>> {
+    // One byte more, so data that inflates to more isn't cut off unnoticed.
+    let mut content = vec!(0u8; size + 1);
+    let mut len = content.len() as c_ulong;
+    let status = unsafe {
+        uncompress(content.as_mut_ptr(), &mut len, data.as_ptr(), data.len() as c_ulong)
+    };
+    if status != 0 || len as usize != size {
+        return None;
+    }
+    content.truncate(size);
+    Some(content)
+}
+
+/// Apply a delta of a binary patch to `base`.  The delta starts with the
+/// sizes of `base` and of the result, followed by instructions to copy
+/// a part of `base` or to insert the bytes that follow.
+fn apply_delta(base: &[u8], delta: &[u8]) -> Option<Vec<u8>> {
+    let mut pos = 0;
+    if delta_size(delta, &mut pos) != Some(base.len()) {
+        return None;
+    }
+    let size = match delta_size(delta, &mut pos) {
+        Some(size) => size,
+        None => return None,
+    };
+    let mut result = Vec::with_capacity(size);
+    while pos < delta.len() {
+        let cmd = delta[pos];
+        pos += 1;
+        if cmd & 0x80 != 0 {
+            // The low bits say which bytes of the offset and the length
+            // follow.
+            let mut offset = 0;
+            let mut length = 0;
+            for bit in 0..7 {
+                if cmd & (1 << bit) == 0 {
+                    continue;
+                }
+                let byte = match delta.get(pos) {
+                    Some(&byte) => byte as usize,
+                    None => return None,
+                };
+                pos += 1;
+                if bit < 4 {
+                    offset |= byte << (8 * bit);
+                } else {
+                    length |= byte << (8 * (bit - 4));
+                }
+            }
+            if length == 0 {
+                length = 0x10000;
+            }
+            if offset + length > base.len() {
+                return None;
+            }
+            result.extend_from_slice(&base[offset..offset + length]);
+        } else if cmd != 0 {
+            let length = cmd as usize;
+            if pos + length > delta.len() {
+                return None;
+            }
+            result.extend_from_slice(&delta[pos..pos + length]);
+            pos += length;
+        } else {
+            return None;
+        }
+    }
+    if result.len() != size {
+        return None;
+    }
+    Some(result)
+}
+
+/// Read one of the sizes at the start of a delta, seven bits a byte.
+fn delta_size(delta: &[u8], pos: &mut usize) -> Option<usize> {
+    let mut size = 0;
+    let mut shift = 0;
+    loop {
+        let byte = match delta.get(*pos) {
+            Some(&byte) => byte,
+            None => return None,
+        };
+        *pos += 1;
+        size |= ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Some(size);
+        }
+    }
+}
+
+/// Decode a line of the data of a binary patch.  Its first character
+/// says how many bytes it has, the rest are groups of five base 85
+/// digits for four bytes each.
+fn decode_85(line: &[u8]) -> Option<Vec<u8>> {
+    let len = match line.first() {
+        Some(&c) if c >= b'A' && c <= b'Z' => (c - b'A') as usize + 1,
+        Some(&c) if c >= b'a' && c <= b'z' => (c - b'a') as usize + 27,
+        _ => return None,
+    };
+    let digits = &line[1..];
+    if digits.len() != (len + 3) / 4 * 5 {
+        return None;
+    }
+    let mut bytes = Vec::new();
+    for group in digits.chunks(5) {
+        let mut value: u64 = 0;
+        for digit in group {
+            match BASE85.iter().position(|d| d == digit) {
+                Some(n) => value = value * 85 + n as u64,
+                None => return None,
+            }
+        }
+        if value > 0xffffffff {
+            return None;
+        }
+        bytes.extend_from_slice(&[(value >> 24) as u8, (value >> 16) as u8, (value >> 8) as u8,
+                                  value as u8]);
+    }
+    bytes.truncate(len);
+    Some(bytes)
+}
+
+/// Rebuild the tree the patch was made against from the blobs named in
+/// it, apply it there, and merge the result into HEAD.  The merged tree
+/// or the index with the conflicts is returned.
+fn three_way(repo: &Repository, head: &Commit, patch: &MailPatch)
+             -> Result<Result<Oid, Index>, Failure> {
+    let head_tree = try!(head.tree());
+    let mut index = try!(Index::new());
+    try!(index.read_tree(&head_tree));
+    for file in patch.files.iter() {
+        match (file.old_path.as_ref(), file.old_id.as_ref()) {
+            (Some(old_path), Some(old_id)) => {
+                let blob = match repo.revparse_single(old_id.as_str())
+                    .and_then(|o| o.peel(ObjectType::Blob)) {
+                    Ok(blob) => blob,
+                    Err(_) => return Err(Failure::NoPreimage(old_path.clone())),
+                };
+                let mode = file.old_mode.unwrap_or(0o100644);
+                try!(index.add(&entry(old_path, blob.id(), mode)));
+            },
+            (Some(old_path), None) => return Err(Failure::NoPreimage(old_path.clone())),
+            (None, _) => if let Some(ref new_path) = file.new_path {
+                if index.get_path(Path::new(new_path), 0).is_some() {
+                    try!(index.remove(Path::new(new_path), 0));
+                }
+            },
+        }
+    }
+    let ancestor_tree = try!(repo.find_tree(try!(index.write_tree_to(repo))));
+    let their_tree = try!(repo.find_tree(try!(apply_to_tree(repo, &ancestor_tree, patch))));
+
+    // libgit2 only merges commits, so the three trees get commits that
+    // nothing refers to.
+    let sig = try!(repo.signature());
+    let ancestor = try!(repo.commit(None, &sig, &sig, "ancestor", &ancestor_tree, &[]));
+    let ancestor = try!(repo.find_commit(ancestor));
+    let ours = try!(repo.commit(None, &sig, &sig, "ours", &head_tree, &[&ancestor]));
+    let theirs = try!(repo.commit(None, &sig, &sig, "theirs", &their_tree, &[&ancestor]));
+    let mut merged = try!(repo.merge_commits(&try!(repo.find_commit(ours)),
+                                             &try!(repo.find_commit(theirs)), None));
+    if merged.iter().any(|e| stage(&e) != 0) {
+        return Ok(Err(merged));
+    }
+    Ok(Ok(try!(merged.write_tree_to(repo))))
+}
+
+/// Check the merged index out with conflict markers, and make it the
+/// index of the repository, so `git status` shows the conflicts.  The
+/// conflicting paths are returned.
+fn write_conflicts(repo: &Repository, merged: &mut Index) -> Result<Vec<String>, Error> {
+    try!(repo.checkout_index(Some(merged), Some(CheckoutBuilder::new()
+                                                .force()
+                                                .allow_conflicts(true)
+                                                .conflict_style_merge(true))));
+    let mut index = try!(repo.index());
+    try!(index.clear());
+    let mut conflicts = Vec::new();
+    for entry in merged.iter() {
+        if stage(&entry) != 0 {
+            let path = String::from_utf8_lossy(&entry.path).into_owned();
+            if !conflicts.contains(&path) {
+                conflicts.push(path);
+            }
+        }
+        try!(index.add(&entry));
+    }
+    try!(index.write());
+    Ok(conflicts)
+}
+
+fn stage(entry: &IndexEntry) -> u16 {
+    (entry.flags >> 12) & 3
+}
+
+fn entry(path: &str, id: Oid, mode: u32) -> IndexEntry {
+    IndexEntry {
+        ctime: IndexTime::new(0, 0),
+        mtime: IndexTime::new(0, 0),
+        dev: 0,
+        ino: 0,
+        mode: mode,
+        uid: 0,
+        gid: 0,
+        file_size: 0,
+        id: id,
+        flags: if path.len() < 0xfff { path.len() as u16 } else { 0xfff },
+        flags_extended: 0,
+        path: path.as_bytes().to_vec(),
+    }
+}
+
+/// Apply the hunks to `content`.  Like `git apply`, a hunk may have moved
+/// from where its header says, but its context has to match exactly.
+/// The index of the hunk that doesn't apply is returned otherwise.
+fn apply_hunks(content: &[u8], hunks: &[Hunk]) -> Result<Vec<u8>, usize> {
+    let lines = split_lines(content);
+    let mut result = Vec::new();
+    let mut pos = 0;
+    let mut offset = 0;
+    for (n, hunk) in hunks.iter().enumerate() {
+        // A hunk that removes or keeps lines can't start before the first.
+        let line = match (hunk.old.is_empty(), hunk.old_start) {
+            (true, start) => start,
+            (false, 0) => return Err(n),
+            (false, start) => start - 1,
+        };
+        let expected = line as isize + offset;
+        let at = try!(find_hunk(&lines, &hunk.old, pos, expected).ok_or(n));
+        for line in lines[pos..at].iter() {
+            result.extend_from_slice(line);
+        }
+        for line in hunk.new.iter() {
+            result.extend_from_slice(line);
+        }
+        pos = at + hunk.old.len();
+        offset = at as isize - line as isize;
+    }
+    for line in lines[pos..].iter() {
+        result.extend_from_slice(line);
+    }
+    Ok(result)
+}
+
+/// Where the `old` lines of a hunk are, at or after `pos`, closest to
+/// `expected`.
+fn find_hunk(lines: &[&[u8]], old: &[Vec<u8>], pos: usize, expected: isize) -> Option<usize> {
+    if lines.len() < pos + old.len() {
+        return None;
+    }
+    let last = (lines.len() - old.len()) as isize;
+    let matches = |at: isize| {
+        at >= pos as isize && at <= last &&
+            old.iter().zip(lines[at as usize..].iter()).all(|(a, b)| &a[..] == *b)
+    };
+    for distance in 0..(lines.len() as isize + 1) {
+        for &at in [expected - distance, expected + distance].iter() {
+            if matches(at) {
+                return Some(at as usize);
+            }
+        }
+    }
+    None
+}
+
+fn split_lines(content: &[u8]) -> Vec<&[u8]> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+    for (i, &byte) in content.iter().enumerate() {
+        if byte == b'\n' {
+            lines.push(&content[start..i + 1]);
+            start = i + 1;
+        }
+    }
+    if start < content.len() {
+        lines.push(&content[start..]);
+    }
+    lines
+}
+
+/// Parse a patch mail, as `git format-patch` or `format::format_series`
+/// write them.
+pub fn parse(contents: &[u8]) -> Result<MailPatch, String> {
+    let lines = split_lines(contents);
+    let text = |line: &[u8]| String::from_utf8_lossy(line).trim_right().to_owned();
+    let mut i = 0;
+    if lines.len() > 0 && lines[0].starts_with(b"From ") {
+        i += 1;
+    }
+
+    let mut header_lines = Vec::new();
+    while i < lines.len() && !text(lines[i]).is_empty() {
+        header_lines.push(text(lines[i]));
+        i += 1;
+    }
+    let headers = format::header_fields(header_lines.join("\n").as_str()).iter()
+        .map(|f| format::unfold(f))
+        .filter_map(|f| f.find(':').map(|colon| (f[..colon].to_lowercase(),
+                                                   f[colon + 1..].trim().to_owned())))
+        .collect::<Vec<_>>();
+    let header = |key: &str| headers.iter().find(|h| h.0 == key).map(|h| decode_header(&h.1));
+
+    let from = try!(header("from").ok_or(String::from("the patch has no From header")));
+    let (name, email) = match (from.find('<'), from.rfind('>')) {
+        (Some(start), Some(end)) if start < end =>
+            (from[..start].trim().trim_matches('"').to_owned(), from[start + 1..end].to_owned()),
+        _ => (from.clone(), from.clone()),
+    };
+    let date = try!(header("date").ok_or(String::from("the patch has no Date header")));
+    let (seconds, offset) = try!(parse_date(date.as_str())
+                                 .ok_or(format!("can't parse the date '{}'", date)));
+    let mut subject = try!(header("subject").ok_or(String::from("the patch has no Subject")));
+    if subject.starts_with('[') {
+        if let Some(end) = subject.find("] ") {
+            subject = subject[end + 2..].to_owned();
+        }
+    }
+
+    let mut body = Vec::new();
+    while i < lines.len() {
+        let line = text(lines[i]);
+        if line == "---" || line.starts_with("diff --git ") {
+            break;
+        }
+        body.push(line);
+        i += 1;
+    }
+    let body = body.join("\n");
+    let message = if body.trim().is_empty() {
+        format!("{}\n", subject)
+    } else {
+        format!("{}\n\n{}\n", subject, body.trim())
+    };
+
+    let files = try!(parse_diff(&lines[i..]));
+    Ok(MailPatch {
+        name: name,
+        email: email,
+        seconds: seconds,
+        offset: offset,
+        message: message,
+        files: files,
+    })
+}
+
+fn parse_diff(lines: &[&[u8]]) -> Result<Vec<FilePatch>, String> {
+    let mut files: Vec<FilePatch> = Vec::new();
+    let mut i = 0;
+    // A range-diff or interdiff goes up to the diffstat.
+    let mut version_diff = false;
+    while i < lines.len() {
+        let line = String::from_utf8_lossy(lines[i]).trim_right_matches('\n').to_owned();
+        i += 1;
+        if line.starts_with("Range-diff against v") || line.starts_with("Interdiff against v") {
+            version_diff = true;
+        } else if version_diff {
+            version_diff = !(line.starts_with(' ') && line.contains(" changed"));
+        } else if line == "-- " {
+            break;
+        } else if line.starts_with("diff --git ") {
+            let paths = &line["diff --git ".len()..];
+            let split = try!(paths.rfind(" b/").ok_or(format!("can't parse '{}'", line)));
+            let old_path = &paths[..split];
+            let old_path = if old_path.starts_with("a/") { &old_path[2..] } else { old_path };
+            files.push(FilePatch {
+                old_path: Some(old_path.to_owned()),
+                new_path: Some(paths[split + 3..].to_owned()),
+                old_mode: None,
+                new_mode: None,
+                old_id: None,
+                new_id: None,
+                binary: false,
+                binary_data: None,
+                hunks: Vec::new(),
+            });
+        } else if let Some(file) = files.last_mut() {
+            let mode = |prefix: &str| u32::from_str_radix(line[prefix.len()..].trim(), 8).ok();
+            if line.starts_with("new file mode ") {
+                file.old_path = None;
+                file.new_mode = mode("new file mode ");
+            } else if line.starts_with("deleted file mode ") {
+                file.new_path = None;
+                file.old_mode = mode("deleted file mode ");
+            } else if line.starts_with("old mode ") {
+                file.old_mode = mode("old mode ");
+            } else if line.starts_with("new mode ") {
+                file.new_mode = mode("new mode ");
+            } else if line.starts_with("rename from ") {
+                file.old_path = Some(line["rename from ".len()..].to_owned());
+            } else if line.starts_with("rename to ") {
+                file.new_path = Some(line["rename to ".len()..].to_owned());
+            } else if line.starts_with("index ") {
+                let mut fields = line["index ".len()..].split(' ');
+                // All zeros stand for a file that doesn't exist.
+                let blob = |id: Option<&str>| id.and_then(|id| {
+                    if id.chars().all(|c| c == '0') { None } else { Some(id.to_owned()) }
+                });
+                let mut ids = fields.next().unwrap_or("").split("..");
+                file.old_id = blob(ids.next());
+                file.new_id = blob(ids.next());
+                if let Some(mode) = fields.next().and_then(|m| u32::from_str_radix(m, 8).ok()) {
+                    file.old_mode = Some(mode);
+                }
+            } else if line.starts_with("GIT binary patch") {
+                let (hunk, consumed) = try!(parse_binary(&lines[i..]));
+                file.binary = true;
+                file.binary_data = Some(hunk);
+                i += consumed;
+            } else if line.starts_with("Binary files ") {
+                file.binary = true;
+            } else if line.starts_with("@@ ") {
+                let (hunk, consumed) = try!(parse_hunk(line.as_str(), &lines[i..]));
+                file.hunks.push(hunk);
+                i += consumed;
+            }
+        }
+    }
+    if files.is_empty() {
+        return Err(String::from("the patch is empty"));
+    }
+    Ok(files)
+}
+
+/// Parse the forward half of a binary patch, the lines after `GIT binary
+/// patch`, and return it with the number of lines it takes up.  The
+/// reverse half that follows isn't needed.
+fn parse_binary(lines: &[&[u8]]) -> Result<(BinaryHunk, usize), String> {
+    let text = |line: &[u8]| String::from_utf8_lossy(line).trim_right().to_owned();
+    let header = lines.first().map_or(String::new(), |&l| text(l));
+    let corrupt = || format!("corrupt binary patch '{}'", header);
+    let (delta, size) = if header.starts_with("literal ") {
+        (false, &header["literal ".len()..])
+    } else if header.starts_with("delta ") {
+        (true, &header["delta ".len()..])
+    } else {
+        return Err(corrupt());
+    };
+    let size = try!(size.parse::<usize>().map_err(|_| corrupt()));
+    let mut data = Vec::new();
+    let mut i = 1;
+    while i < lines.len() {
+        let line = text(lines[i]);
+        i += 1;
+        if line.is_empty() {
+            break;
+        }
+        data.extend(try!(decode_85(line.as_bytes()).ok_or_else(&corrupt)));
+    }
+    Ok((BinaryHunk { delta: delta, size: size, data: data }, i))
+}
+
+/// Parse a hunk with the `header` line, and return it with the number of
+/// lines it takes up after the header.
+fn parse_hunk(header: &str, lines: &[&[u8]]) -> Result<(Hunk, usize), String> {
+    let corrupt = || format!("corrupt hunk '{}'", header);
+    let ranges = try!(header.split("@@").nth(1).ok_or_else(&corrupt));
+    let mut ranges = ranges.split_whitespace();
+    let range = |range: Option<&str>, sign: char| -> Option<(usize, usize)> {
+        let range = match range {
+            Some(range) if range.starts_with(sign) => &range[1..],
+            _ => return None,
+        };
+        let mut split = range.splitn(2, ',');
+        let start = split.next().and_then(|s| s.parse::<usize>().ok());
+        let count = split.next().map_or(Some(1), |s| s.parse::<usize>().ok());
+        match (start, count) {
+            (Some(start), Some(count)) => Some((start, count)),
+            _ => None,
+        }
+    };
+    let (old_start, mut old_count) = try!(range(ranges.next(), '-').ok_or_else(&corrupt));
+    let (_, mut new_count) = try!(range(ranges.next(), '+').ok_or_else(&corrupt));
+
+    let mut hunk = Hunk {
+        header: format!("@@{}@@", header.split("@@").nth(1).unwrap()),
+        old_start: old_start,
+        old: Vec::new(),
+        new: Vec::new(),
+    };
+    let mut i = 0;
+    let mut last = ' ';
+    while i < lines.len() && (old_count > 0 || new_count > 0 || lines[i].starts_with(b"\\")) {
+        let line = lines[i];
+        // Editors like to strip the space of empty context lines.
+        let empty = line == &b"\n"[..];
+        let origin = if empty { ' ' } else { line[0] as char };
+        let content = if empty { line.to_vec() } else { line[1..].to_vec() };
+        match origin {
+            ' ' if old_count > 0 && new_count > 0 => {
+                hunk.old.push(content.clone());
+                hunk.new.push(content);
+                old_count -= 1;
+                new_count -= 1;
+            },
+            '-' if old_count > 0 => {
+                hunk.old.push(content);
+                old_count -= 1;
+            },
+            '+' if new_count > 0 => {
+                hunk.new.push(content);
+                new_count -= 1;
+            },
+            '\\' => {
+                // No newline at the end of the file.
+                if last != '+' {
+                    if let Some(line) = hunk.old.last_mut() {
+                        line.pop();
+                    }
+                }
+                if last != '-' {
+                    if let Some(line) = hunk.new.last_mut() {
+                        line.pop();
+                    }
+                }
+            },
+            _ => return Err(corrupt()),
+        }
+        last = origin;
+        i += 1;
+    }
+    if old_count > 0 || new_count > 0 {
+        return Err(corrupt());
+    }
+    Ok((hunk, i))
+}
+
+/// Parse a date like `Mon, 18 Jan 2016 17:57:18 +0100` into the seconds
+/// since the epoch and the offset in minutes.
+fn parse_date(date: &str) -> Option<(i64, i32)> {
+    let fields = date.split_whitespace().filter(|f| !f.ends_with(',')).collect::<Vec<_>>();
+    if fields.len() < 5 || fields[4].len() != 5 {
+        return None;
+    }
+    let time = fields[3].split(':').map(|f| f.parse::<i64>().ok()).collect::<Option<Vec<_>>>();
+    let sign = if fields[4].starts_with('-') { -1 } else { 1 };
+    match (fields[0].parse::<i64>(), MONTHS.iter().position(|m| *m == fields[1]),
+           fields[2].parse::<i64>(), time, fields[4][1..].parse::<i64>()) {
+        (Ok(day), Some(month), Ok(year), Some(time), Ok(zone)) if time.len() >= 2 => {
+            let offset = sign * (zone / 100 * 60 + zone % 100);
+            let local = format::days(year, month as i64 + 1, day) * 86400 + time[0] * 3600 +
+                time[1] * 60 + time.get(2).cloned().unwrap_or(0);
+            Some((local - offset * 60, offset as i32))
+        },
+        _ => None,
+    }
+}
+
+/// Decode the RFC 2047 encoded words in a header value.
+fn decode_header(value: &str) -> String {
+    let mut decoded = String::new();
+    let mut rest = value;
+    let mut after_word = false;
+    while let Some(start) = rest.find("=?") {
+        let parts = rest[start + 2..].splitn(4, '?').collect::<Vec<_>>();
+        if parts.len() < 4 || !parts[3].starts_with('=') {
+            break;
+        }
+        // Whitespace between two encoded words is not part of the value.
+        if !after_word || !rest[..start].trim().is_empty() {
+            decoded.push_str(&rest[..start]);
+        }
+        let bytes = match parts[1] {
+            "q" | "Q" => decode_q(parts[2]),
+            "b" | "B" => parts[2].from_base64().unwrap_or(Vec::new()),
+            _ => parts[2].as_bytes().to_vec(),
+        };
+        decoded.push_str(&String::from_utf8_lossy(&bytes));
+        rest = &parts[3][1..];
+        after_word = true;
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+fn decode_q(text: &str) -> Vec<u8> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'_' => decoded.push(b' '),
+            b'=' if i + 3 <= bytes.len() => {
+                match str::from_utf8(&bytes[i + 1..i + 3]).ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    },
+                    None => decoded.push(b'='),
+                }
+            },
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    decoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Hunk, apply_delta, apply_hunks, apply_mail, commit_resolved, decode_85,
+                decode_header, inflate, parse, parse_date};
+
+    use error::SubmitError;
+    use format::{self, FormatOptions};
+    use git2::{Oid, Repository, ResetType, Signature, Time};
+    use std::fs::File;
+    use std::io::{Read, Write};
+    use std::path::Path;
+    use tempdir::TempDir;
+
+    const PATCH: &'static [u8] = b"\
+From 889b5224aa79eea855223737e1d1f2a76f5dc9bb Mon Sep 17 00:00:00 2001
+From: =?UTF-8?q?Ren=C3=A9?= Thor <author@example.net>
+Date: Mon, 18 Jan 2016 17:57:18 +0100
+Subject: [PATCH v2 1/2] change a file with
+ a long subject
+
+The body.
+---
+ file | 3 ++-
+ 1 file changed, 2 insertions(+), 1 deletion(-)
+
+diff --git a/file b/file
+index 30d660b..4f3b5a1 100644
+--- a/file
++++ b/file
+@@ -1,2 +1,3 @@
+ one
+-two
++2
++three
+\\ No newline at end of file
+-- 
+git-submit
+
+";
+
+    fn hunk(old_start: usize, old: &[&str], new: &[&str]) -> Hunk {
+        Hunk {
+            header: format!("@@ -{} @@", old_start),
+            old_start: old_start,
+            old: old.iter().map(|l| l.as_bytes().to_vec()).collect(),
+            new: new.iter().map(|l| l.as_bytes().to_vec()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_parse_patch() {
+        let patch = parse(PATCH).unwrap();
+        assert_eq!(patch.name, "René Thor");
+        assert_eq!(patch.email, "author@example.net");
+        assert_eq!((patch.seconds, patch.offset), (1453136238, 60));
+        assert_eq!(patch.message, "change a file with a long subject\n\nThe body.\n");
+        assert_eq!(patch.files.len(), 1);
+        assert_eq!(patch.files[0].old_path, Some(String::from("file")));
+        assert_eq!(patch.files[0].old_id, Some(String::from("30d660b")));
+        let mut expected = hunk(1, &["one\n", "two\n"], &["one\n", "2\n", "three"]);
+        expected.header = String::from("@@ -1,2 +1,3 @@");
+        assert_eq!(patch.files[0].hunks, vec!(expected));
+        assert!(parse(b"Subject: no patch\n\n").is_err());
+
+        // Only the first a/ is the prefix, the second is a directory.
+        let nested = String::from_utf8_lossy(PATCH).replace("a/file b/file", "a/a/file b/a/file");
+        let patch = parse(nested.as_bytes()).unwrap();
+        assert_eq!(patch.files[0].old_path, Some(String::from("a/file")));
+        assert_eq!(patch.files[0].new_path, Some(String::from("a/file")));
+    }
+
+    #[test]
+    fn test_parse_date() {
+        assert_eq!(parse_date("Mon, 18 Jan 2016 17:57:18 +0100"), Some((1453136238, 60)));
+        assert_eq!(parse_date("28 Feb 2016 18:30:00 -0530"), Some((1456704000, -330)));
+        assert_eq!(parse_date("yesterday"), None);
+    }
+
+    #[test]
+    fn test_decode_header() {
+        assert_eq!(decode_header("A U Thor"), "A U Thor");
+        assert_eq!(decode_header("=?UTF-8?q?=C3=86var?= =?UTF-8?B?QXJuZmrDtnLDsA==?="),
+                   "ÆvarArnfjörð");
+    }
+
+    #[test]
+    fn test_decode_binary() {
+        // A line of the binary patch `git diff --binary` shows for this.
+        let data = decode_85(b"QcmZQzO3KVjEUM%J01G(+k^lez").unwrap();
+        assert_eq!(inflate(&data, 9).unwrap(), b"\x00\x02binary\n".to_vec());
+        assert_eq!(inflate(&data, 8), None);
+        assert_eq!(decode_85(b"QcmZQz"), None);
+        assert_eq!(decode_85(b"1cmZQz"), None);
+
+        // Copy "hello " from the base, then insert "there".
+        let delta = b"\x0b\x0b\x90\x06\x05there";
+        assert_eq!(apply_delta(b"hello world", delta).unwrap(), b"hello there".to_vec());
+        assert_eq!(apply_delta(b"hello", delta), None);
+        assert_eq!(apply_delta(b"hello world", b"\x0b\x0b\x90\x06"), None);
+    }
+
+    #[test]
+    fn test_apply_hunks() {
+        let content = b"a\nb\nc\nd\ne\n";
+        // The second hunk moved down by one line.
+        let hunks = vec!(hunk(1, &["a\n"], &["a\n", "x\n"]), hunk(3, &["d\n"], &["y\n"]));
+        assert_eq!(apply_hunks(content, &hunks).unwrap(), b"a\nx\nb\nc\ny\ne\n".to_vec());
+        let hunks = vec!(hunk(1, &["a\n"], &[]), hunk(2, &["z\n"], &[]));
+        assert_eq!(apply_hunks(content, &hunks), Err(1));
+        assert_eq!(apply_hunks(b"", &[hunk(0, &[], &["new\n"])]).unwrap(), b"new\n".to_vec());
+        assert_eq!(apply_hunks(content, &[hunk(0, &["a\n"], &["b\n"])]), Err(0));
+    }
+
+    fn commit_file(repo: &Repository, content: &[u8], message: &str) -> Oid {
+        let path = repo.workdir().unwrap().join("file");
+        File::create(&path).unwrap().write_all(content).unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("file")).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let sig = Signature::new("A U Thor", "author@example.net", &Time::new(1453136238, 60))
+            .unwrap();
+        let parents = match repo.head() {
+            Ok(head) => vec!(repo.find_commit(head.target().unwrap()).unwrap()),
+            Err(_) => Vec::new(),
+        };
+        let parents = parents.iter().collect::<Vec<_>>();
+        repo.commit(Some("HEAD"), &sig, &sig, message, &tree, &parents).unwrap()
+    }
+
+    #[test]
+    fn test_apply_mail() {
+        let tempdir = TempDir::new("git-submit").unwrap();
+        let repo = Repository::init(tempdir.path()).unwrap();
+        let mut config = repo.config().unwrap();
+        config.set_str("user.name", "C O Mitter").unwrap();
+        config.set_str("user.email", "committer@example.com").unwrap();
+        let base = commit_file(&repo, b"1\n2\n3\n4\n5\n6\n7\n8\n", "base");
+        let oid = commit_file(&repo, b"1\n2\n3\nfour\n5\n6\n7\n8\n", "change\n\nWhy.\n");
+        let options = FormatOptions {
+            version: 1,
+            cover_letter: false,
+            subject_prefix: String::from("PATCH"),
+            version_diff: None,
+        };
+        let dir = tempdir.path().join("output");
+        let files = format::format_series(&repo, &[oid], &dir, &options).unwrap();
+
+        let base_obj = repo.find_object(base, None).unwrap();
+        repo.reset(&base_obj, ResetType::Hard, None).unwrap();
+        let applied = repo.find_commit(apply_mail(&repo, &files[0]).unwrap()).unwrap();
+        assert_eq!(applied.tree_id(), repo.find_commit(oid).unwrap().tree_id());
+        assert_eq!(applied.message(), Some("change\n\nWhy.\n"));
+        assert_eq!(applied.author().name(), Some("A U Thor"));
+        assert_eq!(applied.author().when().seconds(), 1453136238);
+        assert_eq!(applied.committer().name(), Some("C O Mitter"));
+
+        // The context changed, but the 3-way merge can deal with it.
+        repo.reset(&base_obj, ResetType::Hard, None).unwrap();
+        commit_file(&repo, b"one\n2\n3\n4\n5\n6\n7\n8\n", "other");
+        apply_mail(&repo, &files[0]).unwrap();
+        let head = repo.head().unwrap().target().unwrap();
+        assert_eq!(repo.find_commit(head).unwrap().message(), Some("change\n\nWhy.\n"));
+
+        // A conflict is left for the user to resolve.
+        repo.reset(&base_obj, ResetType::Hard, None).unwrap();
+        let conflicting = commit_file(&repo, b"1\n2\n3\n4 and a bit\n5\n6\n7\n8\n", "other");
+        match apply_mail(&repo, &files[0]) {
+            Err(SubmitError::ApplyPatch(ref patch)) => assert!(patch.ends_with("change.patch")),
+            _ => panic!("the patch should not apply"),
+        }
+        assert_eq!(repo.head().unwrap().target(), Some(conflicting));
+        assert!(commit_resolved(&repo, &files[0]).is_err());
+        // Nothing was resolved if the index is back at HEAD.
+        repo.reset(&repo.find_object(conflicting, None).unwrap(), ResetType::Mixed, None)
+            .unwrap();
+        match commit_resolved(&repo, &files[0]) {
+            Err(SubmitError::ApplyPatch(_)) => (),
+            _ => panic!("an empty commit should be refused"),
+        }
+        assert_eq!(repo.head().unwrap().target(), Some(conflicting));
+        File::create(tempdir.path().join("file")).unwrap()
+            .write_all(b"1\n2\n3\nfour\n5\n6\n7\n8\n").unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("file")).unwrap();
+        index.write().unwrap();
+        let resolved = repo.find_commit(commit_resolved(&repo, &files[0]).unwrap()).unwrap();
+        assert_eq!(resolved.message(), Some("change\n\nWhy.\n"));
+        assert_eq!(resolved.parent_id(0).unwrap(), conflicting);
+        assert_eq!(resolved.tree_id(), repo.find_commit(oid).unwrap().tree_id());
+    }
+
+    #[test]
+    fn test_apply_mail_binary() {
+        let tempdir = TempDir::new("git-submit").unwrap();
+        let repo = Repository::init(tempdir.path()).unwrap();
+        let mut config = repo.config().unwrap();
+        config.set_str("user.name", "C O Mitter").unwrap();
+        config.set_str("user.email", "committer@example.com").unwrap();
+        let base = commit_file(&repo, b"\x00\x01binary\n", "base");
+        let oid = commit_file(&repo, b"\x00\x02binary\n", "change");
+        let options = FormatOptions {
+            version: 1,
+            cover_letter: false,
+            subject_prefix: String::from("PATCH"),
+            version_diff: None,
+        };
+        let dir = tempdir.path().join("output");
+        let files = format::format_series(&repo, &[oid], &dir, &options).unwrap();
+
+        let base_obj = repo.find_object(base, None).unwrap();
+        repo.reset(&base_obj, ResetType::Hard, None).unwrap();
+        let applied = repo.find_commit(apply_mail(&repo, &files[0]).unwrap()).unwrap();
+        assert_eq!(applied.tree_id(), repo.find_commit(oid).unwrap().tree_id());
+
+        // Data that doesn't result in the blob the patch names is refused.
+        let mut patch = String::new();
+        File::open(&files[0]).unwrap().read_to_string(&mut patch).unwrap();
+        let edited = patch.lines().map(|line| if line.starts_with("index ") {
+            let old_id = line["index ".len()..].split("..").next().unwrap();
+            format!("index {}..1234567 100644", old_id)
+        } else {
+            String::from(line)
+        }).collect::<Vec<_>>().join("\n") + "\n";
+        let edited_path = tempdir.path().join("edited.patch");
+        File::create(&edited_path).unwrap().write_all(edited.as_bytes()).unwrap();
+        repo.reset(&base_obj, ResetType::Hard, None).unwrap();
+        assert!(apply_mail(&repo, &edited_path).is_err());
+
+        // It doesn't apply on top of different content.
+        repo.reset(&base_obj, ResetType::Hard, None).unwrap();
+        commit_file(&repo, b"\x00\x03binary\n", "other");
+        assert!(apply_mail(&repo, &files[0]).is_err());
+    }
+
+    #[test]
+    fn test_apply_mail_latin1() {
+        let tempdir = TempDir::new("git-submit").unwrap();
+        let repo = Repository::init(tempdir.path()).unwrap();
+        let mut config = repo.config().unwrap();
+        config.set_str("user.name", "C O Mitter").unwrap();
+        config.set_str("user.email", "committer@example.com").unwrap();
+        let base = commit_file(&repo, b"caf\xe9\n", "base");
+        let oid = commit_file(&repo, b"caf\xe9 cr\xe8me\n", "change");
+        let options = FormatOptions {
+            version: 1,
+            cover_letter: false,
+            subject_prefix: String::from("PATCH"),
+            version_diff: None,
+        };
+        let dir = tempdir.path().join("output");
+        let files = format::format_series(&repo, &[oid], &dir, &options).unwrap();
+
+        let base_obj = repo.find_object(base, None).unwrap();
+        repo.reset(&base_obj, ResetType::Hard, None).unwrap();
+        let applied = repo.find_commit(apply_mail(&repo, &files[0]).unwrap()).unwrap();
+        assert_eq!(applied.tree_id(), repo.find_commit(oid).unwrap().tree_id());
+    }
+}
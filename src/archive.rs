@@ -0,0 +1,447 @@
+use config::Settings;
+use git2;
+use hyper::Client;
+use hyper::client::Response;
+use hyper::error::Error;
+use hyper::status::StatusCode;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+const GMANE_URL: &'static str = "http://mid.gmane.org";
+const PUBLIC_INBOX_URL: &'static str = "https://lore.kernel.org/all";
+
+/// A mail archive the message referenced by `--in-reply-to` can be
+/// fetched from.
+pub trait Archive {
+    /// Fetch the raw message with the given message-id.
+    fn fetch(&self, message_id: &str) -> Result<String, Error>;
+
+    /// Fetch the raw replies to the message with the given message-id,
+    /// and the replies to those.  Only the local archives can search
+    /// for them.
+    fn replies(&self, message_id: &str) -> Result<Vec<String>, Error> {
+        Err(Error::Io(io::Error::new(io::ErrorKind::Other,
+                                     format!("can't search for the replies to {} in this \
+                                              archive, use a local mbox", message_id))))
+    }
+}
+
+/// gmane's message-id redirector, which points at an article that is
+/// available in raw form under `<article>/raw`.
+pub struct Gmane {
+    base_url: String,
+}
+
+impl Gmane {
+    pub fn new(base_url: &str) -> Gmane {
+        Gmane { base_url: base_url.trim_right_matches('/').to_owned() }
+    }
+}
+
+impl Archive for Gmane {
+    fn fetch(&self, message_id: &str) -> Result<String, Error> {
+        let client = Client::new();
+        let article_res =
+            try!(client.get(format!("{}/{}", self.base_url, message_id).as_str()).send());
+        let raw_res = try!(client.get(
+            format!("{}/raw", article_res.url.serialize()).as_str()).send());
+        read_body(raw_res)
+    }
+}
+
+/// A public-inbox instance (such as lore.kernel.org), which serves raw
+/// messages under `<base>/<message-id>/raw`.
+pub struct PublicInbox {
+    base_url: String,
+}
+
+impl PublicInbox {
+    pub fn new(base_url: &str) -> PublicInbox {
+        PublicInbox { base_url: base_url.trim_right_matches('/').to_owned() }
+    }
+}
+
+impl Archive for PublicInbox {
+    fn fetch(&self, message_id: &str) -> Result<String, Error> {
+        let client = Client::new();
+        let raw_res = try!(client.get(format!("{}/{}/raw", self.base_url,
+                                              message_id.replace("/", "%2F")).as_str()).send());
+        read_body(raw_res)
+    }
+}
+
+/// A local mbox file, such as the one a mail client keeps the list
+/// traffic in.
+pub struct Mbox {
+    path: PathBuf,
+}
+
+impl Mbox {
+    pub fn new(path: &Path) -> Mbox {
+        Mbox { path: path.to_path_buf() }
+    }
+}
+
+impl Mbox {
+    /// The messages in the mbox.  Mail isn't always UTF-8, so the bytes
+    /// that aren't are replaced, instead of failing every lookup.
+    fn messages(&self) -> Result<Vec<String>, Error> {
+        let mut contents = Vec::new();
+        try!(try!(File::open(&self.path)).read_to_end(&mut contents));
+        Ok(split_mbox(String::from_utf8_lossy(contents.as_slice()).as_ref()))
+    }
+}
+
+impl Archive for Mbox {
+    fn fetch(&self, message_id: &str) -> Result<String, Error> {
+        for message in try!(self.messages()) {
+            if matches_message_id(message.as_str(), message_id) {
+                return Ok(message);
+            }
+        }
+        Err(not_found(message_id))
+    }
+
+    fn replies(&self, message_id: &str) -> Result<Vec<String>, Error> {
+        Ok(try!(self.messages()).into_iter().filter(|m| is_reply(m, message_id)).collect())
+    }
+}
+
+/// A local Maildir, where every message is a file in either the `cur`
+/// or the `new` subdirectory.
+pub struct Maildir {
+    path: PathBuf,
+}
+
+impl Maildir {
+    pub fn new(path: &Path) -> Maildir {
+        Maildir { path: path.to_path_buf() }
+    }
+}
+
+impl Maildir {
+    fn messages(&self) -> Result<Vec<String>, Error> {
+        let mut messages = Vec::new();
+        for dir in &["cur", "new"] {
+            let entries = match fs::read_dir(self.path.join(dir)) {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+            for entry in entries {
+                let mut message = Vec::new();
+                try!(try!(File::open(try!(entry).path())).read_to_end(&mut message));
+                messages.push(String::from_utf8_lossy(message.as_slice()).into_owned());
+            }
+        }
+        Ok(messages)
+    }
+}
+
+impl Archive for Maildir {
+    fn fetch(&self, message_id: &str) -> Result<String, Error> {
+        for message in try!(self.messages()) {
+            if matches_message_id(message.as_str(), message_id) {
+                return Ok(message);
+            }
+        }
+        Err(not_found(message_id))
+    }
+
+    fn replies(&self, message_id: &str) -> Result<Vec<String>, Error> {
+        Ok(try!(self.messages()).into_iter().filter(|m| is_reply(m, message_id)).collect())
+    }
+}
+
+/// Split the contents of an mbox file into the messages it contains,
+/// dropping the `From ` separator lines.  `>From ` lines in the messages
+/// lose one `>` again, the way the mbox transport escaped them.
+pub fn split_mbox(contents: &str) -> Vec<String> {
+    let mut messages = Vec::new();
+    let mut current = String::new();
+    let mut in_message = false;
+    let mut previous_blank = true;
+    for line in contents.lines() {
+        if previous_blank && line.starts_with("From ") {
+            if in_message {
+                messages.push(current);
+                current = String::new();
+            }
+            in_message = true;
+        } else if in_message {
+            if line.trim_left_matches('>').starts_with("From ") {
+                current.push_str(&line[1..]);
+            } else {
+                current.push_str(line);
+            }
+            current.push('\n');
+        }
+        previous_blank = line.is_empty();
+    }
+    if in_message {
+        messages.push(current);
+    }
+    messages
+}
+
+/// Find the Message-Id header of a raw message, without the enclosing
+/// angle brackets.
+pub fn message_id(message: &str) -> Option<String> {
+    let mut lines = message.lines();
+    while let Some(line) = lines.next() {
+        if line.is_empty() {
+            break;
+        }
+        if line.to_lowercase().starts_with("message-id:") {
+            let mut value = line[11..].trim();
+            if value.is_empty() {
+                value = lines.next().unwrap_or("").trim();
+            }
+            return Some(value.trim_matches(|c: char| c == '<' || c == '>').to_owned());
+        }
+    }
+    None
+}
+
+/// The message-ids in the In-Reply-To and References headers of a raw
+/// message, without the enclosing angle brackets.
+pub fn references(message: &str) -> Vec<String> {
+    let mut ids = Vec::new();
+    let mut in_thread_headers = false;
+    for line in message.lines() {
+        if line.is_empty() {
+            break;
+        }
+        if !line.starts_with(' ') && !line.starts_with('\t') {
+            let lower = line.to_lowercase();
+            in_thread_headers = lower.starts_with("in-reply-to:") ||
+                lower.starts_with("references:");
+        }
+        if !in_thread_headers {
+            continue;
+        }
+        for part in line.split('<').skip(1) {
+            if let Some(end) = part.find('>') {
+                let id = part[..end].to_owned();
+                if !ids.contains(&id) {
+                    ids.push(id);
+                }
+            }
+        }
+    }
+    ids
+}
+
+/// Whether a raw message is in the thread below the given message-id,
+/// going by its In-Reply-To and References headers.
+fn is_reply(message: &str, message_id: &str) -> bool {
+    let wanted = message_id.trim_matches(|c: char| c == '<' || c == '>');
+    references(message).iter().any(|id| id == wanted)
+}
+
+fn matches_message_id(message: &str, wanted: &str) -> bool {
+    message_id(message).map_or(false, |id| {
+        id == wanted.trim_matches(|c: char| c == '<' || c == '>')
+    })
+}
+
+fn not_found(message_id: &str) -> Error {
+    Error::Io(io::Error::new(io::ErrorKind::NotFound,
+                             format!("message {} not found", message_id)))
+}
+
+fn read_body(mut res: Response) -> Result<String, Error> {
+    if res.status != StatusCode::Ok {
+        return Err(Error::Status);
+    }
+    let mut body = String::new();
+    try!(res.read_to_string(&mut body));
+    Ok(body)
+}
+
+/// Select the archive backend from the `submit.archive` config key,
+/// defaulting to public-inbox, as gmane no longer serves messages by
+/// their Message-Id.  The HTTP backends are located with
+/// `submit.archiveUrl`, the local ones with `submit.archivePath`.
+pub fn from_config(settings: &Settings) -> Result<Box<Archive>, git2::Error> {
+    let url = settings.get_string("archiveUrl");
+    let path = settings.get_path("archivePath");
+    match settings.get_string("archive").as_ref().map(|b| b.as_str()) {
+        None | Some("public-inbox") =>
+            Ok(Box::new(PublicInbox::new(url.as_ref().map_or(PUBLIC_INBOX_URL,
+                                                             |u| u.as_str())))),
+        Some("gmane") =>
+            Ok(Box::new(Gmane::new(url.as_ref().map_or(GMANE_URL, |u| u.as_str())))),
+        Some("mbox") => Ok(Box::new(Mbox::new(&try!(path)))),
+        Some("maildir") => Ok(Box::new(Maildir::new(&try!(path)))),
+        Some(backend) => Err(git2::Error::from_str(
+            format!("unknown archive backend '{}'", backend).as_str())),
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::{Archive, Gmane, Maildir, Mbox, PublicInbox, from_config, message_id,
+                references, split_mbox};
+
+    use config::Settings;
+    use git2::Repository;
+    use std::fs::{self, File};
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use tempdir::TempDir;
+
+    pub const MESSAGE_ID: &'static str = "1453136238-19448-1-git-send-email-t.gummerer@gmail.com";
+
+    pub const MESSAGE: &'static str = "\
+From: Thomas Gummerer <t.gummerer@gmail.com>
+To: git@vger.kernel.org
+Cc: peff@peff.net, bturner@atlassian.com, gitster@pobox.com,
+\tpedrorijo91@gmail.com, Thomas Gummerer <t.gummerer@gmail.com>
+Subject: [PATCH v2] git-send-email: do not double-escape quotes from mutt
+Date: Mon, 18 Jan 2016 17:57:18 +0100
+Message-Id: <1453136238-19448-1-git-send-email-t.gummerer@gmail.com>
+
+Just a test message.
+";
+
+    /// Serve `message` the way both gmane and public-inbox would, so
+    /// lookups can be tested without network access.
+    pub fn serve_message(message: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut buf = [0; 4096];
+                let len = stream.read(&mut buf).unwrap();
+                let request = String::from_utf8_lossy(&buf[..len]).into_owned();
+                let path = request.split(' ').nth(1).unwrap_or("/").to_owned();
+                let response = if path.ends_with("/raw") {
+                    format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                            message.len(), message)
+                } else if path.starts_with("/article") {
+                    String::from("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\
+                                  Connection: close\r\n\r\n")
+                } else {
+                    format!("HTTP/1.1 302 Found\r\nLocation: http://{}/article/1\r\n\
+                             Content-Length: 0\r\nConnection: close\r\n\r\n", addr)
+                };
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        format!("http://{}", addr)
+    }
+
+    #[test]
+    fn test_gmane_fetch() {
+        let archive = Gmane::new(serve_message(MESSAGE).as_str());
+        assert_eq!(archive.fetch(MESSAGE_ID).unwrap(), MESSAGE);
+    }
+
+    #[test]
+    fn test_public_inbox_fetch() {
+        let archive = PublicInbox::new(format!("{}/git/", serve_message(MESSAGE)).as_str());
+        assert_eq!(archive.fetch(MESSAGE_ID).unwrap(), MESSAGE);
+    }
+
+    #[test]
+    fn test_archive_from_config() {
+        let tempdir = TempDir::new("git-submit").unwrap();
+        let repo = Repository::init(tempdir.path()).unwrap();
+        let url = serve_message(MESSAGE);
+        let mut config = repo.config().unwrap();
+        config.set_str("submit.archiveUrl", url.as_str()).unwrap();
+
+        // public-inbox is the default.
+        let settings = Settings::new(&repo, Some("topic")).unwrap();
+        let archive = from_config(&settings).unwrap();
+        assert_eq!(archive.fetch(MESSAGE_ID).unwrap(), MESSAGE);
+        config.set_str("submit.archive", "public-inbox").unwrap();
+        let archive = from_config(&settings).unwrap();
+        assert_eq!(archive.fetch(MESSAGE_ID).unwrap(), MESSAGE);
+
+        config.set_str("submit.archive", "carrier-pigeon").unwrap();
+        assert!(from_config(&settings).is_err());
+        config.set_str("branch.topic.submitArchive", "public-inbox").unwrap();
+        assert!(from_config(&settings).is_ok());
+    }
+
+    #[test]
+    fn test_split_mbox() {
+        let mbox = format!("From 1234 Mon Sep 17 00:00:00 2001\n{}\n\
+                            From 5678 Mon Sep 17 00:00:00 2001\nSubject: second\n\nBody\n\
+                            From the body\n\n>From escaped\n>>From quoted\n", MESSAGE);
+        let messages = split_mbox(mbox.as_str());
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0], format!("{}\n", MESSAGE));
+        assert_eq!(messages[1], "Subject: second\n\nBody\nFrom the body\n\n\
+                                 From escaped\n>From quoted\n");
+    }
+
+    #[test]
+    fn test_message_id() {
+        assert_eq!(message_id(MESSAGE), Some(String::from(MESSAGE_ID)));
+        assert_eq!(message_id("Subject: none\n\nMessage-Id: <body@example.com>\n"), None);
+    }
+
+    #[test]
+    fn test_mbox_fetch() {
+        let tempdir = TempDir::new("git-submit").unwrap();
+        let path = tempdir.path().join("list.mbox");
+        let mut file = File::create(&path).unwrap();
+        file.write_all(b"From 9012 Mon Sep 17 00:00:00 2001\nSubject: caf\xe9\n\nLatin-1\n\n")
+            .unwrap();
+        write!(file, "From 1234 Mon Sep 17 00:00:00 2001\n\
+                      Message-Id: <other@example.com>\n\nOther\n\n\
+                      From 5678 Mon Sep 17 00:00:00 2001\n{}", MESSAGE).unwrap();
+
+        let archive = Mbox::new(&path);
+        assert_eq!(archive.fetch(MESSAGE_ID).unwrap(), MESSAGE);
+        assert!(archive.fetch("missing@example.com").is_err());
+    }
+
+    #[test]
+    fn test_maildir_fetch() {
+        let tempdir = TempDir::new("git-submit").unwrap();
+        fs::create_dir(tempdir.path().join("cur")).unwrap();
+        fs::create_dir(tempdir.path().join("new")).unwrap();
+        let mut file = File::create(tempdir.path().join("new").join("1.host")).unwrap();
+        file.write_all(MESSAGE.as_bytes()).unwrap();
+        File::create(tempdir.path().join("cur").join("2.host")).unwrap()
+            .write_all(b"Subject: caf\xe9\n\nLatin-1\n").unwrap();
+
+        let archive = Maildir::new(tempdir.path());
+        assert_eq!(archive.fetch(format!("<{}>", MESSAGE_ID).as_str()).unwrap(), MESSAGE);
+        assert!(archive.fetch("missing@example.com").is_err());
+    }
+
+    #[test]
+    fn test_replies() {
+        let tempdir = TempDir::new("git-submit").unwrap();
+        fs::create_dir(tempdir.path().join("cur")).unwrap();
+        let reply = format!("Subject: Re: the patch\nReferences: <other@example.com>\n\t<{}>\n\n\
+                             Reviewed-by: Jeff King <peff@peff.net>\n", MESSAGE_ID);
+        File::create(tempdir.path().join("cur").join("1.host")).unwrap()
+            .write_all(MESSAGE.as_bytes()).unwrap();
+        File::create(tempdir.path().join("cur").join("2.host")).unwrap()
+            .write_all(reply.as_bytes()).unwrap();
+
+        let archive = Maildir::new(tempdir.path());
+        assert_eq!(archive.replies(MESSAGE_ID).unwrap(), vec!(reply));
+        assert_eq!(archive.replies("other@example.com").unwrap().len(), 1);
+        assert!(Gmane::new("http://127.0.0.1:1").replies(MESSAGE_ID).is_err());
+    }
+
+    #[test]
+    fn test_references() {
+        let message = "In-Reply-To: <2@example.com>\nSubject: Re: <not@example.com>\n\
+                       References: <cover@example.com>\n\t<1@example.com> <2@example.com>\n\n\
+                       <body@example.com>\n";
+        assert_eq!(references(message), vec!(String::from("2@example.com"),
+                                             String::from("cover@example.com"),
+                                             String::from("1@example.com")));
+        assert_eq!(references("Subject: none\n\n"), Vec::<String>::new());
+    }
+}
@@ -0,0 +1,206 @@
+use git2::{Config, Error, Repository};
+use regex;
+use std::path::PathBuf;
+
+const COVER_LETTER_THRESHOLD: usize = 3;
+
+/// The `submit.*` settings of a repository.  Each `submit.<key>` can be
+/// overridden for a single branch with `branch.<name>.submit<Key>`, so
+/// the conventions of the project a branch is sent to can live in the
+/// repository.
+pub struct Settings {
+    config: Config,
+    branch: Option<String>,
+}
+
+impl Settings {
+    pub fn new(repo: &Repository, branch_name: Option<&str>) -> Result<Settings, Error> {
+        Ok(Settings {
+            config: try!(repo.config()),
+            branch: branch_name.map(String::from),
+        })
+    }
+
+    /// The config keys `key` is looked up as, the most specific first.
+    fn keys(&self, key: &str) -> Vec<String> {
+        let mut keys = Vec::new();
+        if let Some(ref branch) = self.branch {
+            keys.push(format!("branch.{}.submit{}", branch, key));
+        }
+        keys.push(format!("submit.{}", key));
+        keys
+    }
+
+    pub fn get_string(&self, key: &str) -> Option<String> {
+        self.get_string_with_key(key).map(|(value, _)| value)
+    }
+
+    /// The value of `key`, along with the config key it was read from.
+    pub fn get_string_with_key(&self, key: &str) -> Option<(String, String)> {
+        self.keys(key).into_iter()
+            .filter_map(|k| self.config.get_string(k.as_str()).ok().map(|v| (v, k)))
+            .next()
+    }
+
+    pub fn get_path(&self, key: &str) -> Result<PathBuf, Error> {
+        for k in self.keys(key).iter() {
+            if let Ok(path) = self.config.get_path(k) {
+                return Ok(path);
+            }
+        }
+        Err(Error::from_str(format!("submit.{} is not set", key).as_str()))
+    }
+
+    pub fn get_i32(&self, key: &str) -> Option<i32> {
+        self.keys(key).iter().filter_map(|k| self.config.get_i32(k).ok()).next()
+    }
+
+    pub fn get_bool(&self, key: &str) -> Option<bool> {
+        self.keys(key).iter().filter_map(|k| self.config.get_bool(k).ok()).next()
+    }
+
+    /// All values of a key that can be given multiple times.  The values
+    /// for the branch replace the ones for the repository.
+    pub fn get_all(&self, key: &str) -> Result<Vec<String>, Error> {
+        for k in self.keys(key).iter() {
+            // Section and variable names are case insensitive, and
+            // stored in lower case, branch names are not.
+            let (section, name) = k.split_at(k.rfind('.').unwrap() + 1);
+            let pattern = format!("^{}{}$", regex::quote(section), name.to_lowercase());
+            let entries = try!(self.config.entries(Some(pattern.as_str())));
+            let values = (&entries).into_iter()
+                .filter_map(|e| e.value().map(String::from))
+                .collect::<Vec<_>>();
+            if !values.is_empty() {
+                return Ok(values);
+            }
+        }
+        Ok(Vec::new())
+    }
+
+    pub fn to(&self) -> Result<Vec<String>, Error> {
+        self.get_all("to")
+    }
+
+    pub fn cc(&self) -> Result<Vec<String>, Error> {
+        self.get_all("cc")
+    }
+
+    /// Series with at least this many patches get a cover letter.
+    pub fn cover_letter_threshold(&self) -> usize {
+        match self.get_i32("coverLetterThreshold") {
+            Some(threshold) if threshold >= 0 => threshold as usize,
+            _ => COVER_LETTER_THRESHOLD,
+        }
+    }
+
+    /// The prefix in the brackets of the subject, instead of `PATCH`.
+    pub fn subject_prefix(&self) -> Option<String> {
+        self.get_string("subjectPrefix")
+    }
+
+    /// The addresses nothing is sent to: the ones in `submit.suppress`,
+    /// and the user's own `user.email` unless `submit.ccSelf` is set.
+    pub fn suppressed(&self) -> Result<Vec<String>, Error> {
+        let mut suppressed = try!(self.get_all("suppress"));
+        if !self.cc_self() {
+            if let Ok(email) = self.config.get_string("user.email") {
+                suppressed.push(email);
+            }
+        }
+        Ok(suppressed)
+    }
+
+    /// Whether to send the mails to the user as well, when their
+    /// address comes up in the trailers or the thread.
+    pub fn cc_self(&self) -> bool {
+        self.get_bool("ccSelf").unwrap_or(false)
+    }
+
+    /// Whether the To and Cc lists are opened in the editor before the
+    /// series is sent.  Off unless `submit.reviewRecipients` is true, so
+    /// scripts aren't stopped by an editor.
+    pub fn review_recipients(&self) -> bool {
+        self.get_bool("reviewRecipients").unwrap_or(false)
+    }
+
+    /// How the patches are opened in the editor, `each`, `all` or `mbox`.
+    pub fn edit_mode(&self) -> Option<String> {
+        self.get_string("editMode")
+    }
+
+    /// A command that is run with each patch file, and prints more
+    /// addresses to cc it to, one per line.
+    pub fn cc_cmd(&self) -> Option<String> {
+        self.get_string("ccCmd")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Settings;
+
+    use git2::Repository;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_settings_branch_override() {
+        let tempdir = TempDir::new("git-submit").unwrap();
+        let repo = Repository::init(tempdir.path()).unwrap();
+        let mut config = repo.config().unwrap();
+
+        let settings = Settings::new(&repo, Some("Topic/one")).unwrap();
+        assert_eq!(settings.get_string("base"), None);
+        assert_eq!(settings.to().unwrap(), Vec::<String>::new());
+        assert_eq!(settings.cover_letter_threshold(), 3);
+        assert_eq!(settings.subject_prefix(), None);
+        assert_eq!(settings.cc_cmd(), None);
+        assert_eq!(settings.edit_mode(), None);
+
+        config.set_str("submit.to", "list@example.com").unwrap();
+        config.set_str("submit.base", "origin/master").unwrap();
+        config.set_str("submit.subjectPrefix", "PATCH git").unwrap();
+        config.set_str("submit.ccCmd", "./get_maintainer.pl").unwrap();
+        config.set_i32("submit.coverLetterThreshold", 2).unwrap();
+        assert_eq!(settings.to().unwrap(), vec!(String::from("list@example.com")));
+        assert_eq!(settings.subject_prefix(), Some(String::from("PATCH git")));
+        assert_eq!(settings.cc_cmd(), Some(String::from("./get_maintainer.pl")));
+        assert_eq!(settings.cover_letter_threshold(), 2);
+        assert_eq!(settings.get_string_with_key("base"),
+                   Some((String::from("origin/master"), String::from("submit.base"))));
+
+        config.set_str("branch.Topic/one.submitTo", "other@example.com").unwrap();
+        config.set_str("branch.Topic/one.submitSubjectPrefix", "RFC PATCH").unwrap();
+        config.set_i32("branch.Topic/one.submitCoverLetterThreshold", 1).unwrap();
+        assert_eq!(settings.to().unwrap(), vec!(String::from("other@example.com")));
+        assert_eq!(settings.subject_prefix(), Some(String::from("RFC PATCH")));
+        assert_eq!(settings.cover_letter_threshold(), 1);
+        config.set_str("branch.Topic/one.submitBase", "origin/next").unwrap();
+        assert_eq!(settings.get_string_with_key("base"),
+                   Some((String::from("origin/next"),
+                         String::from("branch.Topic/one.submitBase"))));
+
+        let settings = Settings::new(&repo, Some("other")).unwrap();
+        assert_eq!(settings.to().unwrap(), vec!(String::from("list@example.com")));
+        assert_eq!(settings.cc().unwrap(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_settings_suppressed() {
+        let tempdir = TempDir::new("git-submit").unwrap();
+        let repo = Repository::init(tempdir.path()).unwrap();
+        let mut config = repo.config().unwrap();
+        config.set_str("user.email", "me@example.com").unwrap();
+
+        let settings = Settings::new(&repo, Some("topic")).unwrap();
+        assert!(!settings.cc_self());
+        assert!(!settings.review_recipients());
+        assert_eq!(settings.suppressed().unwrap(), vec!(String::from("me@example.com")));
+        config.set_str("submit.suppress", "bot@example.com").unwrap();
+        config.set_bool("branch.topic.submitCcSelf", true).unwrap();
+        assert!(settings.cc_self());
+        config.set_bool("submit.reviewRecipients", true).unwrap();
+        assert!(settings.review_recipients());
+        assert_eq!(settings.suppressed().unwrap(), vec!(String::from("bot@example.com")));
+    }
+}
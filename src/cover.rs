@@ -0,0 +1,138 @@
+use regex::Regex;
+use version::VersionInfo;
+
+const SUBJECT_PLACEHOLDER: &'static str = "*** SUBJECT HERE ***";
+const BLURB_PLACEHOLDER: &'static str = "*** BLURB HERE ***";
+const CHANGES_PLACEHOLDER: &'static str = "*** CHANGES HERE ***";
+
+/// Pull the subject, without the `[PATCH ...]` prefix, and the blurb out
+/// of an edited cover letter.  Either is `None` if it still is the
+/// placeholder the cover letter was generated with.
+pub fn parse(contents: &str) -> (Option<String>, Option<String>) {
+    let mut lines = contents.lines().peekable();
+    let mut subject = None;
+    while let Some(line) = lines.next() {
+        if line.is_empty() {
+            break;
+        }
+        if line.to_lowercase().starts_with("subject:") {
+            let mut value = line[8..].trim().to_owned();
+            while lines.peek().map_or(false, |l| l.starts_with(' ') || l.starts_with('\t')) {
+                value.push(' ');
+                value.push_str(lines.next().unwrap().trim());
+            }
+            if value.starts_with('[') {
+                if let Some(end) = value.find("] ") {
+                    value = value[end + 2..].to_owned();
+                }
+            }
+            subject = Some(value);
+        }
+    }
+
+    // The blurb ends where the shortlog starts.
+    let shortlog = Regex::new(r"^.* \([0-9]+\):$").unwrap();
+    let mut blurb = String::new();
+    for line in lines.take_while(|l| !shortlog.is_match(l)) {
+        blurb.push_str(line);
+        blurb.push('\n');
+    }
+    let blurb = blurb.trim().to_owned();
+    (subject.and_then(|s| if s == SUBJECT_PLACEHOLDER { None } else { Some(s) }),
+     if blurb.is_empty() || blurb == BLURB_PLACEHOLDER { None } else { Some(blurb) })
+}
+
+/// Fill in a freshly generated cover letter with the subject and blurb
+/// of the previous version, and add a section for the changes since.
+pub fn prefill(contents: &str, previous: Option<&VersionInfo>, version: u32) -> String {
+    let mut contents = contents.to_owned();
+    if let Some(subject) = previous.and_then(|p| p.cover_subject.as_ref()) {
+        contents = contents.replace(SUBJECT_PLACEHOLDER, subject.as_str());
+    }
+    if version > 1 {
+        // Only the changes in the latest version are interesting, the
+        // ones listed in the previous blurb are dropped.
+        let blurb = previous.and_then(|p| p.cover_blurb.as_ref()).map_or(
+            String::from(BLURB_PLACEHOLDER),
+            |b| b.split("\nChanges since v").next().unwrap_or("").trim().to_owned());
+        contents = contents.replace(BLURB_PLACEHOLDER,
+                                    format!("{}\n\nChanges since v{}:\n\n\
+                                             {}", blurb, version - 1, CHANGES_PLACEHOLDER)
+                                    .as_str());
+    }
+    contents
+}
+
+/// The first placeholder that is still left in the cover letter, if any.
+pub fn placeholder(contents: &str) -> Option<&'static str> {
+    [SUBJECT_PLACEHOLDER, BLURB_PLACEHOLDER, CHANGES_PLACEHOLDER].iter()
+        .find(|p| contents.contains(*p))
+        .map(|p| *p)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, placeholder, prefill};
+
+    use version::VersionInfo;
+
+    const COVER_LETTER: &'static str = "\
+From 889b5224aa79eea855223737e1d1f2a76f5dc9bb Mon Sep 17 00:00:00 2001
+From: A U Thor <author@example.net>
+Date: Mon, 18 Jan 2016 17:57:18 +0100
+Subject: [PATCH v2 0/2] *** SUBJECT HERE ***
+
+*** BLURB HERE ***
+
+A U Thor (2):
+  commit 2
+  commit 3
+
+ 2 | 1 +
+ 3 | 1 +
+ 2 files changed, 2 insertions(+)
+";
+
+    #[test]
+    fn test_parse_cover_letter() {
+        assert_eq!(parse(COVER_LETTER), (None, None));
+
+        let edited = COVER_LETTER
+            .replace("*** SUBJECT HERE ***", "add two files to the\n repository")
+            .replace("*** BLURB HERE ***", "They are needed.\n\nReally.");
+        assert_eq!(parse(edited.as_str()),
+                   (Some(String::from("add two files to the repository")),
+                    Some(String::from("They are needed.\n\nReally."))));
+    }
+
+    #[test]
+    fn test_prefill_cover_letter() {
+        assert_eq!(prefill(COVER_LETTER, None, 1), COVER_LETTER);
+
+        let mut info = VersionInfo::parse("");
+        info.cover_subject = Some(String::from("add two files"));
+        info.cover_blurb = Some(String::from("They are needed.\n\n\
+                                              Changes since v1:\n\n - typo fixes"));
+        let prefilled = prefill(COVER_LETTER, Some(&info), 3);
+        assert!(prefilled.contains("Subject: [PATCH v2 0/2] add two files\n\n\
+                                    They are needed.\n\nChanges since v2:\n\n\
+                                    *** CHANGES HERE ***\n\nA U Thor (2):\n"));
+
+        let prefilled = prefill(COVER_LETTER, None, 2);
+        assert!(prefilled.contains("*** SUBJECT HERE ***\n\n*** BLURB HERE ***\n\n\
+                                    Changes since v1:\n"));
+    }
+
+    #[test]
+    fn test_placeholder() {
+        assert_eq!(placeholder(COVER_LETTER), Some("*** SUBJECT HERE ***"));
+        let edited = COVER_LETTER.replace("*** SUBJECT HERE ***", "add two files");
+        assert_eq!(placeholder(edited.as_str()), Some("*** BLURB HERE ***"));
+        let mut info = VersionInfo::parse("");
+        info.cover_blurb = Some(String::from("They are needed."));
+        let prefilled = prefill(edited.as_str(), Some(&info), 2);
+        assert_eq!(placeholder(prefilled.as_str()), Some("*** CHANGES HERE ***"));
+        let edited = prefilled.replace("*** CHANGES HERE ***", " - typo fixes");
+        assert_eq!(placeholder(edited.as_str()), None);
+    }
+}
@@ -0,0 +1,137 @@
+use email::results::ParsingError;
+use git2;
+use hyper;
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Everything that can make a `git submit` run fail.  Each class of
+/// failure maps to its own exit code, so scripts can tell them apart.
+#[derive(Debug)]
+pub enum SubmitError {
+    Usage(String),
+    DirtyWorktree,
+    Git(git2::Error),
+    Io(io::Error),
+    Archive(hyper::error::Error),
+    Parse(ParsingError),
+    FormatPatch(String),
+    ApplyPatch(String),
+    SendEmail(String),
+}
+
+impl SubmitError {
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            SubmitError::Usage(_) => 2,
+            SubmitError::DirtyWorktree => 3,
+            SubmitError::Git(_) => 4,
+            SubmitError::Io(_) => 5,
+            SubmitError::Archive(_) => 6,
+            SubmitError::Parse(_) => 7,
+            SubmitError::FormatPatch(_) => 8,
+            SubmitError::ApplyPatch(_) => 9,
+            SubmitError::SendEmail(_) => 10,
+        }
+    }
+}
+
+impl fmt::Display for SubmitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SubmitError::Usage(ref msg) => write!(f, "{}", msg),
+            SubmitError::DirtyWorktree =>
+                write!(f, "git-submit can't be run with changes in the working tree"),
+            SubmitError::Git(ref e) => write!(f, "{}", e),
+            SubmitError::Io(ref e) => write!(f, "{}", e),
+            SubmitError::Archive(ref e) =>
+                write!(f, "could not fetch the message from the archive: {}", e),
+            SubmitError::Parse(ref e) => write!(f, "could not parse the message: {}", e),
+            SubmitError::FormatPatch(ref msg) =>
+                write!(f, "formatting the patches failed: {}", msg),
+            SubmitError::ApplyPatch(ref patch) => write!(f, "could not apply {}", patch),
+            SubmitError::SendEmail(ref msg) => write!(f, "sending the patches failed: {}", msg),
+        }
+    }
+}
+
+impl Error for SubmitError {
+    fn description(&self) -> &str {
+        match *self {
+            SubmitError::Usage(ref msg) => msg.as_str(),
+            SubmitError::DirtyWorktree => "changes in the working tree",
+            SubmitError::Git(ref e) => e.description(),
+            SubmitError::Io(ref e) => e.description(),
+            SubmitError::Archive(ref e) => e.description(),
+            SubmitError::Parse(ref e) => e.description(),
+            SubmitError::FormatPatch(_) => "formatting the patches failed",
+            SubmitError::ApplyPatch(_) => "could not apply patch",
+            SubmitError::SendEmail(_) => "sending the patches failed",
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            SubmitError::Git(ref e) => Some(e),
+            SubmitError::Io(ref e) => Some(e),
+            SubmitError::Archive(ref e) => Some(e),
+            SubmitError::Parse(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<git2::Error> for SubmitError {
+    fn from(e: git2::Error) -> SubmitError {
+        SubmitError::Git(e)
+    }
+}
+
+impl From<io::Error> for SubmitError {
+    fn from(e: io::Error) -> SubmitError {
+        SubmitError::Io(e)
+    }
+}
+
+impl From<hyper::error::Error> for SubmitError {
+    fn from(e: hyper::error::Error) -> SubmitError {
+        SubmitError::Archive(e)
+    }
+}
+
+impl From<ParsingError> for SubmitError {
+    fn from(e: ParsingError) -> SubmitError {
+        SubmitError::Parse(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SubmitError;
+
+    use git2;
+    use std::io;
+
+    #[test]
+    fn test_exit_codes_differ() {
+        let errors = vec!(SubmitError::Usage(String::from("usage")),
+                          SubmitError::DirtyWorktree,
+                          SubmitError::from(git2::Error::from_str("git")),
+                          SubmitError::from(io::Error::new(io::ErrorKind::Other, "io")),
+                          SubmitError::ApplyPatch(String::from("0001-a.patch")),
+                          SubmitError::SendEmail(String::from("smtp")));
+        let mut codes = errors.iter().map(|e| e.exit_code()).collect::<Vec<_>>();
+        codes.sort();
+        codes.dedup();
+        assert_eq!(codes.len(), errors.len());
+        assert!(!codes.contains(&0) && !codes.contains(&101));
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(format!("{}", SubmitError::DirtyWorktree),
+                   "git-submit can't be run with changes in the working tree");
+        assert_eq!(format!("{}", SubmitError::ApplyPatch(String::from("0001-a.patch"))),
+                   "could not apply 0001-a.patch");
+    }
+}
@@ -0,0 +1,564 @@
+use address;
+use email::Mailbox;
+use error::SubmitError;
+use git2::{self, Commit, Diff, DiffFormat, DiffOptions, Oid, Repository, Signature, Time};
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+const DAYS: [&'static str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+pub const MONTHS: [&'static str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug",
+                                    "Sep", "Oct", "Nov", "Dec"];
+const SIGNATURE: &'static str = "-- \ngit-submit\n\n";
+/// The longest an RFC 2047 encoded word may be.
+const ENCODED_WORD_LENGTH: usize = 75;
+
+/// How a series is formatted, what `git format-patch` took options for.
+pub struct FormatOptions {
+    pub version: u32,
+    pub cover_letter: bool,
+    pub subject_prefix: String,
+    /// A range-diff or interdiff against the previous version, which
+    /// goes into the cover letter, or below the `---` of a single patch.
+    pub version_diff: Option<String>,
+}
+
+/// Write the commits in `revs` (newest first, like the revwalk returns
+/// them) as patch mails to `dir`, the way `git format-patch` would, and
+/// return the files in order.
+pub fn format_series(repo: &Repository, revs: &[Oid], dir: &Path, options: &FormatOptions)
+                     -> Result<Vec<PathBuf>, SubmitError> {
+    // Patches left over from an earlier run would be taken for part of
+    // the series.
+    if dir.exists() {
+        try!(fs::remove_dir_all(dir));
+    }
+    try!(fs::create_dir_all(dir));
+    let mut commits = Vec::new();
+    for rev in revs.iter().rev() {
+        commits.push(try!(repo.find_commit(*rev)));
+    }
+    let total = commits.len();
+    let numbered = total > 1 || options.cover_letter;
+    let version_prefix = match options.version {
+        1 => String::new(),
+        version => format!("v{}-", version),
+    };
+
+    let mut files = Vec::new();
+    if options.cover_letter {
+        let path = dir.join(format!("{}0000-cover-letter.patch", version_prefix));
+        let contents = try!(cover_letter(repo, &mut commits, options));
+        try!(try!(File::create(&path)).write_all(contents.as_bytes()));
+        files.push(path);
+    }
+    for (i, commit) in commits.iter_mut().enumerate() {
+        let subject = subject(options, if numbered { Some((i + 1, total)) } else { None },
+                              commit.summary().unwrap_or(""));
+        let path = dir.join(format!("{}{:04}-{}.patch", version_prefix, i + 1,
+                                    sanitize(commit.summary().unwrap_or(""))));
+        let version_diff = if total == 1 && !options.cover_letter {
+            options.version_diff.as_ref()
+        } else {
+            None
+        };
+        let contents = try!(format_commit(repo, commit, subject.as_str(), version_diff));
+        try!(try!(File::create(&path)).write_all(&contents));
+        files.push(path);
+    }
+    Ok(files)
+}
+
+fn subject(options: &FormatOptions, number: Option<(usize, usize)>, summary: &str) -> String {
+    let mut prefix = options.subject_prefix.clone();
+    if options.version > 1 {
+        prefix.push_str(format!(" v{}", options.version).as_str());
+    }
+    if let Some((i, total)) = number {
+        prefix.push_str(format!(" {}/{}", i, total).as_str());
+    }
+    format!("[{}] {}", prefix.trim(), summary)
+}
+
+/// A commit as a patch mail.  The diff is kept as it is, whatever the
+/// encoding of the files is, so it is bytes rather than a string.
+fn format_commit(repo: &Repository, commit: &Commit, subject: &str,
+                 version_diff: Option<&String>) -> Result<Vec<u8>, SubmitError> {
+    let tree = try!(commit.tree());
+    let parent_tree = match commit.parent(0) {
+        Ok(parent) => Some(try!(parent.tree())),
+        Err(_) => None,
+    };
+    let diff = try!(repo.diff_tree_to_tree(parent_tree.as_ref(), Some(&tree),
+                                           Some(DiffOptions::new().show_binary(true))));
+    let mut body = message_body(commit.message().unwrap_or(""));
+    if !body.is_empty() {
+        body.push('\n');
+    }
+    body.push_str("---\n");
+    if let Some(version_diff) = version_diff {
+        body.push_str(version_diff.as_str());
+        body.push('\n');
+    }
+    body.push_str(try!(diffstat(&diff)).as_str());
+    body.push('\n');
+    let mut body = body.into_bytes();
+    body.extend_from_slice(&try!(diff_text(&diff)));
+    body.extend_from_slice(SIGNATURE.as_bytes());
+
+    let mut patch = try!(headers(commit.id(), &commit.author(), &commit.author().when(),
+                                 subject, &body)).into_bytes();
+    patch.extend_from_slice(&body);
+    Ok(patch)
+}
+
+fn cover_letter(repo: &Repository, commits: &mut Vec<Commit>, options: &FormatOptions)
+                -> Result<String, SubmitError> {
+    let head = commits[commits.len() - 1].id();
+    let sender = try!(repo.signature());
+    let subject = subject(options, Some((0, commits.len())), "*** SUBJECT HERE ***");
+    let mut letter = String::from("*** BLURB HERE ***\n\n");
+
+    let mut shortlog: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for commit in commits.iter_mut() {
+        let author = String::from(commit.author().name().unwrap_or(""));
+        let summary = String::from(commit.summary().unwrap_or(""));
+        shortlog.entry(author).or_insert(Vec::new()).push(summary);
+    }
+    for (author, summaries) in shortlog {
+        letter.push_str(format!("{} ({}):\n", author, summaries.len()).as_str());
+        for summary in summaries {
+            letter.push_str(format!("  {}\n", summary).as_str());
+        }
+        letter.push('\n');
+    }
+
+    let old_tree = match commits[0].parent(0) {
+        Ok(parent) => Some(try!(parent.tree())),
+        Err(_) => None,
+    };
+    let new_tree = try!(try!(repo.find_commit(head)).tree());
+    let diff = try!(repo.diff_tree_to_tree(old_tree.as_ref(), Some(&new_tree), None));
+    letter.push_str(try!(diffstat(&diff)).as_str());
+    letter.push('\n');
+    if let Some(ref version_diff) = options.version_diff {
+        letter.push_str(version_diff.as_str());
+        letter.push('\n');
+    }
+    letter.push_str(SIGNATURE);
+    let headers = try!(headers(head, &sender, &now(), subject.as_str(), letter.as_bytes()));
+    Ok(headers + letter.as_str())
+}
+
+/// The headers of a patch mail.  The charset is declared if anything in
+/// `body`, the commit message or the diff, isn't plain ASCII.
+fn headers(oid: Oid, author: &Signature, when: &Time, subject: &str, body: &[u8])
+           -> Result<String, SubmitError> {
+    let name = author.name().unwrap_or("");
+    let email = author.email().unwrap_or("");
+    // Names that need encoding can't need quoting as well.
+    let from = if is_ascii(name.as_bytes()) {
+        address::format(Mailbox::new_with_name(name.to_owned(), email.to_owned()))
+    } else {
+        format!("{} <{}>", encode_header(name), email)
+    };
+    let mut headers = format!("From {} Mon Sep 17 00:00:00 2001\n\
+                               From: {}\n\
+                               Date: {}\n\
+                               Subject: {}\n",
+                              oid, from, rfc2822(when), encode_header(subject));
+    if !is_ascii(body) {
+        headers.push_str("MIME-Version: 1.0\n\
+                          Content-Type: text/plain; charset=UTF-8\n\
+                          Content-Transfer-Encoding: 8bit\n");
+    }
+    headers.push('\n');
+    Ok(headers)
+}
+
+/// The commit message without the subject paragraph.
+fn message_body(message: &str) -> String {
+    let mut lines = message.lines().skip_while(|l| l.trim().is_empty());
+    for line in lines.by_ref() {
+        if line.trim().is_empty() {
+            break;
+        }
+    }
+    let body = lines.skip_while(|l| l.trim().is_empty()).collect::<Vec<_>>().join("\n");
+    body.trim_right().to_owned()
+}
+
+/// The diff in the format `git diff` shows it.
+pub fn diff_text(diff: &Diff) -> Result<Vec<u8>, SubmitError> {
+    let mut text = Vec::new();
+    try!(diff.print(DiffFormat::Patch, |_, _, line| {
+        match line.origin() {
+            '+' | '-' | ' ' => text.push(line.origin() as u8),
+            _ => (),
+        }
+        text.extend_from_slice(line.content());
+        true
+    }));
+    Ok(text)
+}
+
+fn diffstat(diff: &Diff) -> Result<String, SubmitError> {
+    let stats = try!(diff.stats());
+    let buf = try!(stats.to_buf(git2::DIFF_STATS_FULL | git2::DIFF_STATS_INCLUDE_SUMMARY, 72));
+    Ok(String::from_utf8_lossy(&*buf).into_owned())
+}
+
+fn now() -> Time {
+    let sig = Signature::now("git-submit", "git-submit").unwrap();
+    sig.when()
+}
+
+fn is_ascii(value: &[u8]) -> bool {
+    value.iter().all(|&b| b < 0x80)
+}
+
+/// Encode a header value as RFC 2047 encoded words if it isn't plain
+/// ASCII.  No word is longer than `ENCODED_WORD_LENGTH`, and a character
+/// is never split across two of them.  The words are folded onto lines
+/// of their own.
+fn encode_header(value: &str) -> String {
+    if is_ascii(value.as_bytes()) {
+        return value.to_owned();
+    }
+    let (prefix, suffix) = ("=?UTF-8?q?", "?=");
+    let max = ENCODED_WORD_LENGTH - prefix.len() - suffix.len();
+    let mut words = vec!(String::new());
+    for c in value.chars() {
+        let mut encoded = String::new();
+        for byte in c.to_string().bytes() {
+            match byte {
+                b' ' => encoded.push('_'),
+                b'a'...b'z' | b'A'...b'Z' | b'0'...b'9' | b'.' | b'-' =>
+                    encoded.push(byte as char),
+                _ => encoded.push_str(format!("={:02X}", byte).as_str()),
+            }
+        }
+        if words.last().unwrap().len() + encoded.len() > max {
+            words.push(String::new());
+        }
+        words.last_mut().unwrap().push_str(encoded.as_str());
+    }
+    words.iter()
+        .map(|w| format!("{}{}{}", prefix, w, suffix))
+        .collect::<Vec<_>>()
+        .join("\n ")
+}
+
+/// The date of a commit as it is shown in mail headers.
+pub fn rfc2822(time: &Time) -> String {
+    let offset = time.offset_minutes() as i64;
+    let secs = time.seconds() + offset * 60;
+    let days = if secs >= 0 { secs / 86400 } else { (secs - 86399) / 86400 };
+    let (year, month, day) = civil(days);
+    let of_day = secs - days * 86400;
+    format!("{}, {} {} {} {:02}:{:02}:{:02} {}{:02}{:02}",
+            DAYS[((days % 7 + 11) % 7) as usize], day, MONTHS[(month - 1) as usize], year,
+            of_day / 3600, of_day % 3600 / 60, of_day % 60,
+            if offset < 0 { '-' } else { '+' }, offset.abs() / 60, offset.abs() % 60)
+}
+
+/// The days since the epoch of a date, Howard Hinnant's
+/// `days_from_civil`.
+pub fn days(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let yoe = year - era * 400;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// The year, month and day of a day since the epoch.  This is Howard
+/// Hinnant's `civil_from_days`.
+pub fn civil(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = if days >= 0 { days } else { days - 146096 } / 146097;
+    let doe = days - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    (yoe + era * 400 + if month <= 2 { 1 } else { 0 }, month, day)
+}
+
+/// Split the patches of a series that were put into a single mbox back
+/// up.  Each one starts at its `From <sha> Mon Sep 17 00:00:00 2001`
+/// line, anything before the first one is dropped.
+pub fn split_series(mbox: &[u8]) -> Vec<Vec<u8>> {
+    let re = Regex::new("^From [0-9a-f]{40} Mon Sep 17 00:00:00 2001\r?$").unwrap();
+    let mut patches: Vec<Vec<u8>> = Vec::new();
+    let mut lines = mbox.split(|&b| b == b'\n').collect::<Vec<_>>();
+    if mbox.is_empty() || mbox.ends_with(b"\n") {
+        lines.pop();
+    }
+    for line in lines {
+        if re.is_match(&String::from_utf8_lossy(line)) {
+            patches.push(Vec::new());
+        }
+        if let Some(patch) = patches.last_mut() {
+            patch.extend_from_slice(line);
+            patch.push(b'\n');
+        }
+    }
+    patches
+}
+
+/// Split a patch mail into its headers, which are plain ASCII, and
+/// the body after the empty line, which is left as it is.
+pub fn split_mail(contents: &[u8]) -> (String, &[u8]) {
+    let (end, start) = match contents.windows(2).position(|w| w == b"\n\n") {
+        Some(pos) => (pos + 1, pos + 2),
+        None => (contents.len(), contents.len()),
+    };
+    (String::from_utf8_lossy(&contents[..end]).into_owned(), &contents[start..])
+}
+
+/// The fields in `headers`, see `split_mail`, each with the lines it is
+/// folded over.
+pub fn header_fields(headers: &str) -> Vec<String> {
+    let mut fields: Vec<String> = Vec::new();
+    for line in headers.lines() {
+        if line.is_empty() {
+            break;
+        }
+        if line.starts_with(' ') || line.starts_with('\t') {
+            if let Some(field) = fields.last_mut() {
+                field.push('\n');
+                field.push_str(line);
+                continue;
+            }
+        }
+        fields.push(line.to_owned());
+    }
+    fields
+}
+
+/// A header field, see `header_fields`, on a single line.
+pub fn unfold(field: &str) -> String {
+    field.lines().map(|l| l.trim()).collect::<Vec<_>>().join(" ")
+}
+
+/// Turn a subject into the part of a file name `git format-patch` would
+/// use for it.
+fn sanitize(subject: &str) -> String {
+    let mut name = String::new();
+    let mut dash = false;
+    for c in subject.chars() {
+        if (c as u32) < 128 && (c.is_alphanumeric() || c == '.' || c == '_') {
+            if dash && !name.is_empty() {
+                name.push('-');
+            }
+            dash = false;
+            name.push(c);
+        } else {
+            dash = true;
+        }
+    }
+    let mut name = name.trim_left_matches('.').to_owned();
+    name.truncate(57);
+    name.trim_right_matches(|c: char| c == '.' || c == '-').to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FormatOptions, civil, days, encode_header, format_series, header_fields, headers,
+                message_body, rfc2822, sanitize, split_mail, split_series, unfold};
+
+    use git2::{Oid, Repository, Signature, Time};
+    use std::fs::{self, File};
+    use std::io::{Read, Write};
+    use std::path::Path;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_rfc2822() {
+        assert_eq!(civil(0), (1970, 1, 1));
+        assert_eq!(days(2016, 2, 29), 16860);
+        assert_eq!(civil(days(1969, 12, 31)), (1969, 12, 31));
+        assert_eq!(rfc2822(&Time::new(1453136238, 60)), "Mon, 18 Jan 2016 17:57:18 +0100");
+        assert_eq!(rfc2822(&Time::new(1456704000, -330)), "Sun, 28 Feb 2016 18:30:00 -0530");
+    }
+
+    #[test]
+    fn test_sanitize() {
+        assert_eq!(sanitize("commit 2"), "commit-2");
+        assert_eq!(sanitize("git-send-email: do not double-escape quotes from mutt"),
+                   "git-send-email-do-not-double-escape-quotes-from-mutt");
+        assert_eq!(sanitize("..fix \"quoting\"."), "fix-quoting");
+    }
+
+    #[test]
+    fn test_message_body() {
+        assert_eq!(message_body("subject\n"), "");
+        assert_eq!(message_body("subject\nmore subject\n\nbody\n\nmore body\n\n"),
+                   "body\n\nmore body");
+    }
+
+    #[test]
+    fn test_encode_header() {
+        assert_eq!(encode_header("A U Thor"), "A U Thor");
+        assert_eq!(encode_header("Ævar Arnfjörð"), "=?UTF-8?q?=C3=86var_Arnfj=C3=B6r=C3=B0?=");
+
+        let encoded = encode_header("[PATCH 1/2] löng sübject thät nëeds to be split över \
+                                     several encoded words");
+        assert_eq!(encoded, "=?UTF-8?q?=5BPATCH_1=2F2=5D_l=C3=B6ng_s=C3=BCbject_th=C3=A4t_n=C3=AB\
+                             eds_t?=\n =?UTF-8?q?o_be_split_=C3=B6ver_several_encoded_words?=");
+        assert!(encoded.lines().all(|l| l.trim().len() <= 75));
+    }
+
+    #[test]
+    fn test_headers() {
+        let sig = Signature::new("A U Thor", "author@example.net", &Time::new(1453136238, 60))
+            .unwrap();
+        let oid = Oid::from_str("0123456789abcdef0123456789abcdef01234567").unwrap();
+        let plain = headers(oid, &sig, &sig.when(), "subject", b"---\n+plain\n").unwrap();
+        assert!(!plain.contains("MIME-Version"));
+        let diff = headers(oid, &sig, &sig.when(), "subject", b"---\n+caf\xc3\xa9\n").unwrap();
+        assert!(diff.ends_with("Subject: subject\nMIME-Version: 1.0\n\
+                                Content-Type: text/plain; charset=UTF-8\n\
+                                Content-Transfer-Encoding: 8bit\n\n"));
+
+        let sig = Signature::new("King, Jeff", "peff@peff.net", &Time::new(1453136238, 60))
+            .unwrap();
+        let quoted = headers(oid, &sig, &sig.when(), "subject", b"").unwrap();
+        assert!(quoted.contains("\nFrom: \"King, Jeff\" <peff@peff.net>\n"));
+    }
+
+    #[test]
+    fn test_split_series() {
+        let first = "From 0000000000000000000000000000000000000000 Mon Sep 17 00:00:00 2001\n\
+                     Subject: [PATCH 0/1] cover\n\nFrom the blurb.\n";
+        let second = "From 0123456789abcdef0123456789abcdef01234567 Mon Sep 17 00:00:00 2001\n\
+                      Subject: [PATCH 1/1] patch\n\n\
+                      From 0123456789abcdef0123456789abcdef01234567 on\n";
+        assert_eq!(split_series(format!("{}{}", first, second).as_bytes()),
+                   vec!(first.as_bytes().to_vec(), second.as_bytes().to_vec()));
+        assert_eq!(split_series(format!("junk\n{}", second).as_bytes()),
+                   vec!(second.as_bytes().to_vec()));
+        assert_eq!(split_series(b""), Vec::<Vec<u8>>::new());
+
+        let latin1 = b"From 0123456789abcdef0123456789abcdef01234567 Mon Sep 17 00:00:00 2001\n\
+                       Subject: [PATCH] latin-1\n\n+caf\xe9\n";
+        assert_eq!(split_series(latin1), vec!(latin1.to_vec()));
+    }
+
+    #[test]
+    fn test_split_mail() {
+        let (headers, body) = split_mail(b"From: A U Thor <author@example.net>\n\
+                                           Subject: test\n\nbody \xe9\n");
+        assert_eq!(headers, "From: A U Thor <author@example.net>\nSubject: test\n");
+        assert_eq!(body, &b"body \xe9\n"[..]);
+        assert_eq!(split_mail(b"Subject: test\n"), (String::from("Subject: test\n"), &b""[..]));
+    }
+
+    #[test]
+    fn test_header_fields() {
+        let fields = header_fields("From: =?UTF-8?q?J=C3=B6rg?=\n \
+                                    =?UTF-8?q?_Name?= <j@example.com>\n\
+                                    Subject: test\n\tfolded\n\nbody\n");
+        assert_eq!(fields, vec!(String::from("From: =?UTF-8?q?J=C3=B6rg?=\n \
+                                              =?UTF-8?q?_Name?= <j@example.com>"),
+                                String::from("Subject: test\n\tfolded")));
+        assert_eq!(unfold(fields[0].as_str()),
+                   "From: =?UTF-8?q?J=C3=B6rg?= =?UTF-8?q?_Name?= <j@example.com>");
+        assert_eq!(unfold(fields[1].as_str()), "Subject: test folded");
+    }
+
+    #[test]
+    fn test_format_series() {
+        let tempdir = TempDir::new("git-submit").unwrap();
+        let repo = Repository::init(tempdir.path()).unwrap();
+        let mut config = repo.config().unwrap();
+        config.set_str("user.name", "C O Mitter").unwrap();
+        config.set_str("user.email", "committer@example.com").unwrap();
+        let sig = Signature::new("A U Thor", "author@example.net", &Time::new(1453136238, 60))
+            .unwrap();
+        let mut revs = Vec::new();
+        let mut parents = Vec::new();
+        for (i, message) in ["first", "second file\n\nWith a body.\n"].iter().enumerate() {
+            let name = format!("{}", i + 1);
+            File::create(tempdir.path().join(&name)).unwrap()
+                .write_all(b"Hello it's me!\n").unwrap();
+            let mut index = repo.index().unwrap();
+            index.add_path(Path::new(&name)).unwrap();
+            let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+            let oid = {
+                let parent_refs = parents.iter().collect::<Vec<_>>();
+                repo.commit(Some("HEAD"), &sig, &sig, message, &tree, &parent_refs).unwrap()
+            };
+            parents = vec!(repo.find_commit(oid).unwrap());
+            revs.insert(0, oid);
+        }
+
+        let options = FormatOptions {
+            version: 2,
+            cover_letter: true,
+            subject_prefix: String::from("PATCH"),
+            version_diff: Some(String::from("Interdiff against v1:\n")),
+        };
+        let dir = tempdir.path().join("output");
+        let files = format_series(&repo, &revs[..1], &dir, &options).unwrap();
+        let names = files.iter().map(|f| f.file_name().unwrap().to_str().unwrap().to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!("v2-0000-cover-letter.patch", "v2-0001-second-file.patch"));
+
+        let mut patch = String::new();
+        File::open(&files[1]).unwrap().read_to_string(&mut patch).unwrap();
+        assert_eq!(patch, format!("From {} Mon Sep 17 00:00:00 2001\n\
+                                   From: A U Thor <author@example.net>\n\
+                                   Date: Mon, 18 Jan 2016 17:57:18 +0100\n\
+                                   Subject: [PATCH v2 1/1] second file\n\
+                                   \n\
+                                   With a body.\n\
+                                   ---\n \
+                                   2 | 1 +\n \
+                                   1 file changed, 1 insertion(+)\n \
+                                   create mode 100644 2\n\
+                                   \n\
+                                   diff --git a/2 b/2\n\
+                                   new file mode 100644\n\
+                                   index 0000000..30d660b\n\
+                                   --- /dev/null\n\
+                                   +++ b/2\n\
+                                   @@ -0,0 +1 @@\n\
+                                   +Hello it's me!\n\
+                                   -- \ngit-submit\n\n", revs[0]));
+
+        let mut cover = String::new();
+        File::open(&files[0]).unwrap().read_to_string(&mut cover).unwrap();
+        assert!(cover.contains("From: C O Mitter <committer@example.com>\n"));
+        assert!(cover.contains("Subject: [PATCH v2 0/1] *** SUBJECT HERE ***\n\n\
+                                *** BLURB HERE ***\n\n\
+                                A U Thor (1):\n  second file\n\n \
+                                2 | 1 +\n"));
+        assert!(cover.contains("Interdiff against v1:\n\n-- \n"));
+
+        let options = FormatOptions {
+            version: 1,
+            cover_letter: false,
+            subject_prefix: String::from("RFC PATCH"),
+            version_diff: None,
+        };
+        let files = format_series(&repo, &revs, &tempdir.path().join("v1"), &options).unwrap();
+        assert_eq!(files.len(), 2);
+        let mut patch = String::new();
+        File::open(&files[0]).unwrap().read_to_string(&mut patch).unwrap();
+        assert!(patch.contains("\nSubject: [RFC PATCH 1/2] first\n\n---\n"));
+
+        // Formatting into the same directory again replaces what was there.
+        format_series(&repo, &revs, &dir, &options).unwrap();
+        let mut names = fs::read_dir(&dir).unwrap()
+            .map(|e| e.unwrap().file_name().to_str().unwrap().to_owned())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, vec!("0001-first.patch", "0002-second-file.patch"));
+    }
+}
@@ -3,69 +3,214 @@ extern crate email;
 extern crate getopts;
 extern crate git2;
 extern crate hyper;
+extern crate openssl;
 extern crate regex;
+extern crate rustc_serialize;
 extern crate tempdir;
 
-use email::Address;
-use email::Mailbox;
-use email::MimeMessage;
-use getopts::Options;
-use git2::{Branch, Error, ObjectType, Oid, Reference, Repository, ResetType, StatusOptions};
+mod address;
+mod apply;
+mod archive;
+mod config;
+mod cover;
+mod error;
+mod format;
+mod recipients;
+mod state;
+mod thread;
+mod trailers;
+mod transport;
+mod version;
+
+use archive::Archive;
+use config::Settings;
+use error::SubmitError;
+use getopts::{Matches, Options};
+use git2::{Branch, BranchType, Error, ObjectType, Oid, Reference, Repository, ResetType,
+           StatusOptions};
 use git2::build::CheckoutBuilder;
-use hyper::Client;
 use regex::Regex;
+use state::State;
 use std::env;
-use std::fs;
-use std::io::{self, Read};
-use std::process::{Command, Stdio};
-use std::str;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+use std::time::{SystemTime, UNIX_EPOCH};
+use thread::ThreadContext;
+use version::VersionInfo;
+
+/// Find the commit the series is based on.  An explicit `--base` wins,
+/// then the upstream of the branch and then `submit.base` (or
+/// `branch.<name>.submitBase`).  `None`
+/// means none of them is set, and `revs_to_send` has to guess.  The
+/// second element describes where the base came from.
+fn find_base(repo: &Repository, branch: &Branch, base: Option<String>)
+             -> Result<Option<(Oid, String)>, Error> {
+    let (rev, source) = match base {
+        Some(base) => (base, String::from("--base")),
+        None => match branch.upstream() {
+            Ok(upstream) => {
+                let name = try!(upstream.name()).unwrap_or("upstream").to_owned();
+                (name.clone(), format!("the upstream {}", name))
+            },
+            Err(_) => {
+                let settings = try!(Settings::new(repo, try!(branch.name())));
+                match settings.get_string_with_key("base") {
+                    Some(base) => base,
+                    None => return Ok(None),
+                }
+            },
+        },
+    };
+    let base = try!(try!(repo.revparse_single(rev.as_str())).peel(ObjectType::Commit)).id();
+    let tip = try!(branch.get().target().ok_or(Error::from_str("branch has no target")));
+    // The base may have moved on since the branch was started, only the
+    // commits that aren't in it yet are part of the series.
+    Ok(Some((try!(repo.merge_base(base, tip)), format!("{} from {}", rev, source))))
+}
 
-fn revs_to_send(repo: &Repository) -> Result<Vec<Oid>, Error> {
+/// The commits on `tip` to send, newest first.  Without a base this
+/// stops at the first commit another branch points to, or at the root
+/// commit.
+fn revs_to_send(repo: &Repository, tip: &Reference, base: Option<Oid>)
+                -> Result<Vec<Oid>, Error> {
     let mut revwalk = try!(repo.revwalk());
-    try!(revwalk.push_head());
-    let ref_oids = try!(branches(&repo)).iter().map(|x| x.target().unwrap()).collect::<Vec<_>>();
+    revwalk.set_sorting(git2::SORT_TOPOLOGICAL);
+    try!(revwalk.push(try!(tip.target().ok_or(Error::from_str("branch has no target")))));
+    if let Some(base) = base {
+        try!(revwalk.hide(base));
+        return Ok(revwalk.collect());
+    }
+    let ref_oids = try!(branches(&repo, tip)).iter()
+        .map(|x| x.target().unwrap())
+        .collect::<Vec<_>>();
     Ok(revwalk.take_while(|x| !ref_oids.contains(x)).collect())
 }
 
-fn branches(repo: &Repository) -> Result<Vec<Reference>, Error> {
-    let refs = try!(repo.references());
-    let head = repo.head().unwrap();
-    Ok(refs.filter(|x| x.is_branch() && x != &head).collect())
-}
-
-fn current_branch<'a>(repo: &'a Repository) -> Result<Branch<'a>, Error> {
-    let branches = try!(repo.branches(None));
-    // TODO(tg): This won't be very nice if we have multiple branches pointing to head.
-    // We should error out in such a case.
-    for branch in branches {
-        let (branch_unwrap, _) = branch;
-        if branch_unwrap.is_head() {
-            return Ok(branch_unwrap);
+/// Refuse series that contain merges, patches can only describe linear
+/// history.
+fn check_linear(repo: &Repository, revs: &Vec<Oid>) -> Result<(), SubmitError> {
+    let mut merges = Vec::new();
+    for rev in revs {
+        let mut commit = try!(repo.find_commit(*rev));
+        if commit.parent_ids().count() > 1 {
+            merges.push(format!("  {} {}", rev, commit.summary().unwrap_or("")));
         }
     }
-    Err(Error::from_str("no branch pointing to HEAD"))
+    if merges.is_empty() {
+        return Ok(());
+    }
+    Err(SubmitError::Usage(format!("the series contains merge commits, which can't be sent \
+                                    as patches:\n{}", merges.join("\n"))))
 }
 
-fn set_path(repo: &Repository) {
-    let repo_root = repo.workdir();
-    env::set_current_dir(repo_root.unwrap()).unwrap();
+/// All branches but `tip`.
+fn branches<'a>(repo: &'a Repository, tip: &Reference) -> Result<Vec<Reference<'a>>, Error> {
+    let refs = try!(repo.references());
+    Ok(refs.filter(|x| x.is_branch() && x != tip).collect())
 }
 
-fn format_patches(revs: &Vec<Oid>, branch_name: &str, version: u32) {
-    let mut command = Command::new("git");
-    command.arg("format-patch");
-    command.arg("-o");
-    command.arg(format!("output-{}", branch_name).replace("/", "_"));
-    if revs.len() >= 3 {
-        command.arg("--cover-letter");
+/// The branch HEAD points to.  With `name` set, that branch is used
+/// instead, as long as it points at the same commit, and is checked out
+/// in case HEAD was detached.
+fn current_branch<'a>(repo: &'a Repository, name: Option<String>) -> Result<Branch<'a>, Error> {
+    let head = try!(repo.head());
+    match name {
+        Some(name) => {
+            let branch = try!(repo.find_branch(name.as_str(), BranchType::Local));
+            if branch.get().target() != head.target() {
+                return Err(Error::from_str(
+                    format!("branch '{}' does not point at HEAD", name).as_str()));
+            }
+            try!(repo.set_head(try!(branch.get().name().ok_or(
+                Error::from_str("branch name not valid")))));
+            Ok(branch)
+        },
+        None => {
+            if !head.is_branch() {
+                return Err(Error::from_str("HEAD is detached, check out a branch or use \
+                                            --branch=<branch>"));
+            }
+            Ok(Branch::wrap(head))
+        },
     }
-    if version > 1 {
-        command.arg(format!("-v{}", version));
+}
+
+fn set_path(repo: &Repository) -> Result<(), io::Error> {
+    match repo.workdir() {
+        Some(repo_root) => env::set_current_dir(repo_root),
+        None => Err(io::Error::new(io::ErrorKind::Other,
+                                   "git-submit can't be run in a bare repository")),
     }
-    command.arg(format!("{}~..{}", revs[revs.len() - 1], revs[0]));
-    let output = command.output().unwrap_or_else(|e| panic!("error: {}", e));
-    if !output.status.success() {
-        panic!("format-patch failed");
+}
+
+/// Format the patches into the `patch_dir`.  `version_diff` is the
+/// range-diff or interdiff comparing the series to the previous
+/// version, see `version_diff`.  It goes into the cover letter, or below
+/// the `---` of a single patch.
+fn format_patches(repo: &Repository, revs: &Vec<Oid>, branch_name: &str, version: u32,
+                  version_diff: Option<String>, settings: &Settings) -> Result<(), SubmitError> {
+    let options = format::FormatOptions {
+        version: version,
+        cover_letter: revs.len() >= settings.cover_letter_threshold() ||
+            (version_diff.is_some() && revs.len() > 1),
+        subject_prefix: settings.subject_prefix().unwrap_or(String::from("PATCH")),
+        version_diff: version_diff,
+    };
+    try!(format::format_series(repo, revs, &patch_dir(repo, branch_name, version), &options));
+    Ok(())
+}
+
+/// The range-diff or interdiff of `revs` against the previous version,
+/// as chosen by `submit.versionDiff`.  libgit2 has no range-diff, so
+/// that one still comes from `git range-diff`.
+fn version_diff(repo: &Repository, branch_name: &str, version: u32, revs: &Vec<Oid>)
+                -> Result<Option<String>, SubmitError> {
+    if version == 1 {
+        return Ok(None);
+    }
+    let previous = try!(try!(repo.revparse_single(
+        version::tag_name(branch_name, version - 1).as_str())).peel(ObjectType::Commit)).id();
+    let head = revs[0];
+    let mode = try!(Settings::new(repo, Some(branch_name))).get_string("versionDiff")
+        .unwrap_or(String::from("range-diff"));
+    match mode.as_str() {
+        "range-diff" => {
+            // With the base of the previous version known, the ranges
+            // don't have to be guessed.
+            let previous_base = try!(VersionInfo::read(repo, branch_name, version - 1))
+                .and_then(|info| info.base);
+            let base = try!(repo.find_commit(revs[revs.len() - 1])).parent_id(0).ok();
+            let mut command = Command::new("git");
+            command.arg("range-diff").arg("--no-color");
+            match (previous_base, base) {
+                (Some(previous_base), Some(base)) => {
+                    command.arg(format!("{}..{}", previous_base, previous));
+                    command.arg(format!("{}..{}", base, head));
+                },
+                _ => {
+                    command.arg(format!("{}...{}", previous, head));
+                },
+            }
+            let output = try!(command.output());
+            if !output.status.success() {
+                return Err(SubmitError::FormatPatch(
+                    String::from_utf8_lossy(&output.stderr).into_owned()));
+            }
+            Ok(Some(format!("Range-diff against v{}:\n{}", version - 1,
+                            String::from_utf8_lossy(&output.stdout))))
+        },
+        "interdiff" => {
+            let old_tree = try!(try!(repo.find_commit(previous)).tree());
+            let new_tree = try!(try!(repo.find_commit(head)).tree());
+            let diff = try!(repo.diff_tree_to_tree(Some(&old_tree), Some(&new_tree), None));
+            Ok(Some(format!("Interdiff against v{}:\n{}", version - 1,
+                            String::from_utf8_lossy(&try!(format::diff_text(&diff))))))
+        },
+        "none" => Ok(None),
+        _ => Err(SubmitError::Usage(format!("unknown submit.versionDiff '{}'", mode))),
     }
 }
 
@@ -90,19 +235,21 @@ fn find_version(repo: &Repository, branch_name: &str) -> Result<u32, Error> {
     Ok(max)
 }
 
-fn tag_version(repo: &Repository, branch_name: &str, version: u32) -> Result<(), Error> {
+fn tag_version(repo: &Repository, branch_name: &str, version: u32, info: &VersionInfo)
+               -> Result<(), Error> {
     let branch = try!(repo.revparse_single(branch_name));
-    try!(repo.tag_lightweight(format!("{}-v{}", branch_name, version).as_str(), &branch, true));
+    let tagger = try!(repo.signature());
+    try!(repo.tag(version::tag_name(branch_name, version).as_str(), &branch, &tagger,
+                  info.message(branch_name, version).as_str(), true));
     Ok(())
 }
 
-fn send_emails(repo: &Repository, branch_name: &str, in_reply_to: Option<String>,
-               to: Vec<String>, cc: Vec<String>) -> Result<(), io::Error> {
+fn send_emails(repo: &Repository, branch_name: &str, version: u32, in_reply_to: Option<String>,
+               to: Vec<String>, cc: Vec<String>) -> Result<(), SubmitError> {
     let mut command = Command::new("git");
     command.arg("send-email");
     if to.is_empty() && cc.is_empty() {
-        return Err(io::Error::new(io::ErrorKind::Other,
-                                  "please specify at least one address"));
+        return Err(SubmitError::Usage(String::from("please specify at least one address")));
     }
     if !to.is_empty() {
         for addr in to {
@@ -117,32 +264,34 @@ fn send_emails(repo: &Repository, branch_name: &str, in_reply_to: Option<String>
     if in_reply_to.is_some() {
         command.arg(format!("--in-reply-to={}", in_reply_to.unwrap()));
     }
-
-    let path = repo.workdir().unwrap();
-    let patch_files = try!(fs::read_dir(format!("{}/output-{}/", path.to_str().unwrap_or("./"),
-                                                branch_name.replace("/", "_"))));
-    let mut file_list = Vec::new();
-    for file in patch_files {
-        let f = try!(file);
-        if f.path().to_str().is_some() {
-            let st = f.path();
-            file_list.push(st)
-        }
+    // The addresses in the trailers are in the Cc headers of the patches
+    // already, without the suppressed ones.
+    command.arg("--suppress-cc=body");
+    if !try!(Settings::new(repo, Some(branch_name))).cc_self() {
+        command.arg("--suppress-cc=self");
     }
-    file_list.sort();
-    for file in file_list {
+
+    for file in try!(patch_files(repo, branch_name, version)) {
         command.arg(file);
     }
     let output = try!(command.output());
-    println!("{}", str::from_utf8(output.stdout.as_slice()).unwrap());
-    println!("{}", str::from_utf8(output.stderr.as_slice()).unwrap());
+    println!("{}", String::from_utf8_lossy(output.stdout.as_slice()));
+    println!("{}", String::from_utf8_lossy(output.stderr.as_slice()));
+    if !output.status.success() {
+        return Err(SubmitError::SendEmail(String::from("git send-email failed")));
+    }
     Ok(())
 }
 
-fn edit_patches(repo: &Repository, branch_name: &str) -> Result<(), io::Error> {
-    let path = repo.workdir().unwrap();
-    let patch_files = try!(fs::read_dir(format!("{}/output-{}/", path.to_str().unwrap_or("./"),
-                                                branch_name.replace("/", "_"))));
+/// Where the patches of a version are written to and edited.  It is
+/// inside the git directory, so they don't show up in the working tree.
+fn patch_dir(repo: &Repository, branch_name: &str, version: u32) -> PathBuf {
+    repo.path().join("submit").join(branch_name).join(format!("v{}", version))
+}
+
+fn patch_files(repo: &Repository, branch_name: &str, version: u32)
+               -> Result<Vec<PathBuf>, io::Error> {
+    let patch_files = try!(fs::read_dir(patch_dir(repo, branch_name, version)));
     let mut file_list = Vec::new();
     for file in patch_files {
         let f = try!(file);
@@ -154,209 +303,846 @@ fn edit_patches(repo: &Repository, branch_name: &str) -> Result<(), io::Error> {
         }
     }
     file_list.sort();
+    Ok(file_list)
+}
 
-    for file in file_list {
-        let editor = match env::var("EDITOR") {
-            Ok(editor) => editor,
-            Err(_) => return Err(io::Error::new(io::ErrorKind::Other,
-                                                "EDITOR environment variable has to be set")),
-        };
-        let mut editor_split = editor.split(" ");
-        let mut command = Command::new(editor_split.next().unwrap());
-        for es in editor_split {
-            command.arg(es);
+/// The cover letter among the patch files, if there is one.
+fn cover_letter(repo: &Repository, branch_name: &str, version: u32)
+                -> Result<Option<PathBuf>, io::Error> {
+    let re = Regex::new("^(v[0-9]+-)?0000-").unwrap();
+    Ok(try!(patch_files(repo, branch_name, version)).into_iter().find(|file| {
+        file.file_name().map_or(false, |name| re.is_match(name.to_string_lossy().as_ref()))
+    }))
+}
+
+/// Carry the subject and blurb of the previous version's cover letter
+/// over to the new one.
+fn prefill_cover_letter(repo: &Repository, branch_name: &str, version: u32)
+                        -> Result<(), SubmitError> {
+    let path = match try!(cover_letter(repo, branch_name, version)) {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let previous = match version {
+        1 => None,
+        _ => try!(VersionInfo::read(repo, branch_name, version - 1)),
+    };
+    let mut contents = String::new();
+    try!(try!(File::open(&path)).read_to_string(&mut contents));
+    let contents = cover::prefill(contents.as_str(), previous.as_ref(), version);
+    try!(try!(File::create(&path)).write_all(contents.as_bytes()));
+    Ok(())
+}
+
+/// Make sure none of the placeholders are left in the cover letter, so
+/// it isn't sent before it is written.
+fn check_cover_letter(repo: &Repository, branch_name: &str, version: u32)
+                      -> Result<(), SubmitError> {
+    let path = match try!(cover_letter(repo, branch_name, version)) {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let mut contents = String::new();
+    try!(try!(File::open(&path)).read_to_string(&mut contents));
+    match cover::placeholder(contents.as_str()) {
+        Some(placeholder) => Err(SubmitError::Usage(format!(
+            "the cover letter still contains '{}', not sending the series", placeholder))),
+        None => Ok(()),
+    }
+}
+
+fn print_dry_run(repo: &Repository, branch_name: &str, version: u32,
+                 in_reply_to: &Option<String>, to: &Vec<String>, cc: &Vec<String>)
+                 -> Result<(), SubmitError> {
+    println!("Dry run, not tagging the series and not sending:");
+    if let Some(ref message_id) = *in_reply_to {
+        println!("In-Reply-To: {}", message_id);
+    }
+    for addr in to {
+        println!("To: {}", addr);
+    }
+    for addr in cc {
+        println!("Cc: {}", addr);
+    }
+    for file in try!(patch_files(repo, branch_name, version)) {
+        if let Some(name) = file.file_name() {
+            println!("  {}", name.to_string_lossy());
+        }
+        let mut contents = Vec::new();
+        try!(try!(File::open(&file)).read_to_end(&mut contents));
+        let (headers, _) = format::split_mail(&contents);
+        for addr in try!(transport::cc_header(headers.as_str())) {
+            println!("    Cc: {}", addr);
         }
-        command.arg(file);
-        command.stdout(Stdio::inherit());
-        try!(command.output());
     }
     Ok(())
 }
 
+/// Open the patches in the editor.  `submit.editMode` chooses between
+/// opening them one after the other (`each`, the default), all at once
+/// (`all`), or as a single mbox that is split up again (`mbox`).
+fn edit_patches(repo: &Repository, branch_name: &str, version: u32, settings: &Settings)
+                -> Result<(), SubmitError> {
+    let files = try!(patch_files(repo, branch_name, version));
+    match settings.edit_mode().as_ref().map(|m| m.as_str()) {
+        None | Some("each") => {
+            for file in files.iter() {
+                try!(run_editor(&[file]));
+            }
+        },
+        Some("all") => try!(run_editor(&files)),
+        Some("mbox") => try!(edit_as_mbox(repo, &files)),
+        Some(mode) => return Err(SubmitError::Usage(format!("unknown edit mode '{}'", mode))),
+    }
+    Ok(())
+}
+
+/// Edit the whole series as one mbox, and write the patches back to
+/// their files.  The patches can be changed, but not added or removed.
+fn edit_as_mbox(repo: &Repository, files: &[PathBuf]) -> Result<(), SubmitError> {
+    let path = repo.path().join("SUBMIT_SERIES.mbox");
+    let mut mbox = Vec::new();
+    for file in files {
+        try!(try!(File::open(file)).read_to_end(&mut mbox));
+    }
+    try!(try!(File::create(&path)).write_all(&mbox));
+    let result = edit_mbox(&path, files);
+    cleanup(fs::remove_file(&path));
+    result
+}
+
+/// Run the editor on the mbox at `path` and split it up into `files`
+/// again, see `edit_as_mbox`.
+fn edit_mbox(path: &Path, files: &[PathBuf]) -> Result<(), SubmitError> {
+    try!(run_editor(&[path]));
+    let mut contents = Vec::new();
+    try!(try!(File::open(path)).read_to_end(&mut contents));
+    let patches = format::split_series(&contents);
+    if patches.len() != files.len() {
+        return Err(SubmitError::Usage(format!(
+            "the edited series has {} patches instead of {}", patches.len(), files.len())));
+    }
+    for (file, patch) in files.iter().zip(patches.iter()) {
+        try!(try!(File::create(file)).write_all(patch));
+    }
+    Ok(())
+}
+
+fn run_editor<P: AsRef<Path>>(files: &[P]) -> Result<(), io::Error> {
+    let editor = match env::var("EDITOR") {
+        Ok(editor) => editor,
+        Err(_) => return Err(io::Error::new(io::ErrorKind::Other,
+                                            "EDITOR environment variable has to be set")),
+    };
+    let mut editor_split = editor.split(" ");
+    let mut command = Command::new(editor_split.next().unwrap());
+    for es in editor_split {
+        command.arg(es);
+    }
+    for file in files {
+        command.arg(file.as_ref());
+    }
+    command.stdout(Stdio::inherit());
+    try!(command.output());
+    Ok(())
+}
+
+/// Let the user go through the To and Cc lists in the editor before
+/// the series is sent, as the addresses picked up from the thread are
+/// not always the right ones.  The addresses in the Cc headers of the
+/// patch `files` are shown as well, and the ones that are removed are
+/// taken out of the files.  Patch-Cc addresses the user adds are cc'd on
+/// the whole series, as there is no telling which patches they are for.
+fn review_recipients(repo: &Repository, files: &[PathBuf], to: &[String], cc: &[String])
+                     -> Result<(Vec<String>, Vec<String>), SubmitError> {
+    let mut file_cc = Vec::new();
+    for file in files {
+        let mut contents = Vec::new();
+        try!(try!(File::open(file)).read_to_end(&mut contents));
+        let (headers, _) = format::split_mail(&contents);
+        file_cc.extend(try!(transport::cc_header(headers.as_str())));
+    }
+    let (patch_cc, _) = address::dedup(file_cc.clone(), Vec::new(), &to.iter()
+                                       .chain(cc.iter())
+                                       .cloned()
+                                       .collect::<Vec<_>>());
+
+    let path = repo.path().join("SUBMIT_RECIPIENTS");
+    let result = edit_recipients(&path, recipients::format(to, cc, &patch_cc).as_str());
+    if path.exists() {
+        cleanup(fs::remove_file(&path));
+    }
+    let (to, mut cc, new_patch_cc) = try!(result);
+    let known = file_cc.iter().map(|a| address::key(a)).collect::<Vec<_>>();
+    let kept = to.iter()
+        .chain(cc.iter())
+        .chain(new_patch_cc.iter())
+        .map(|a| address::key(a))
+        .collect::<Vec<_>>();
+    let removed = file_cc.iter()
+        .filter(|a| !kept.contains(&address::key(a)))
+        .cloned()
+        .collect::<Vec<_>>();
+    cc.extend(new_patch_cc.into_iter().filter(|a| !known.contains(&address::key(a))));
+    if to.is_empty() && cc.is_empty() {
+        return Err(SubmitError::Usage(String::from("no recipients left, not sending the series")));
+    }
+    try!(transport::strip_cc(files, &removed));
+    Ok((to, cc))
+}
+
+/// Write the recipients as `listed` to `path`, run the editor on them
+/// and read them back, see `review_recipients`.
+fn edit_recipients(path: &Path, listed: &str)
+                   -> Result<(Vec<String>, Vec<String>, Vec<String>), SubmitError> {
+    try!(try!(File::create(path)).write_all(listed.as_bytes()));
+    try!(run_editor(&[path]));
+    let mut contents = String::new();
+    try!(try!(File::open(path)).read_to_string(&mut contents));
+    recipients::parse(contents.as_str())
+}
+
 fn is_clean(repo: &Repository) -> Result<bool, Error> {
     let statuses = try!(repo.statuses(Some(&mut StatusOptions::new())));
     Ok(statuses.len() == 0)
 }
 
-fn rebuild_branch(repo: &Repository, original_revs: &Vec<Oid>, branch_name: &str)
-                  -> Result<(), Error> {
+fn rebuild_branch(repo: &Repository, original_revs: &Vec<Oid>, branch_name: &str, version: u32)
+                  -> Result<(), SubmitError> {
     let obj = try!(repo.revparse_single(format!("{}~", original_revs[original_revs.len() - 1])
                                         .as_str()));
     try!(repo.reset(&obj, ResetType::Hard, Some(&mut CheckoutBuilder::new())));
-    let path = repo.workdir().unwrap();
-    let patch_files = match fs::read_dir(format!("{}/output-{}/", path.to_str().unwrap_or("./"),
-                                                 branch_name.replace("/", "_"))) {
-        Ok(files) => files,
-        Err(_) => return Err(Error::from_str("could not read patch files")),
-    };
-    let re = Regex::new("(v[0-9]+-)?0000.*?").unwrap();
+    apply_patches(repo, branch_name, version, None)
+}
 
-    let mut file_list = Vec::new();
-    for file in patch_files {
-        let f = match file {
-            Ok(file) => file,
-            Err(e) => return Err(Error::from_str(format!("{}", e).as_str())),
-        };
-        if f.path().to_str().is_some() {
-            let st = f.path();
-            file_list.push(st)
-        } else {
-            return Err(Error::from_str("path is not valid utf-8"));
+/// Apply the patches in the `patch_dir`, skipping the ones up to and
+/// including `after`, which have been applied already.
+fn apply_patches(repo: &Repository, branch_name: &str, version: u32, after: Option<&str>)
+                 -> Result<(), SubmitError> {
+    let cover = try!(cover_letter(repo, branch_name, version));
+    for file in try!(patch_files(repo, branch_name, version)) {
+        if Some(&file) == cover.as_ref() {
+            continue;
         }
-    }
-    file_list.sort();
-
-    for file in file_list {
         match file.to_str() {
-            Some(filename) => if re.is_match(filename) {
+            Some(filename) => if after.map_or(false, |a| filename <= a) {
                 continue;
             },
             None => continue,
         };
-        let mut command = Command::new("git");
-        command.arg("am");
-        command.arg("--3way");
-        command.arg(file.to_str().unwrap());
-        match command.output() {
-            Ok(output) => if !output.status.success() {
-                return Err(Error::from_str("git am unsuccessful"));
-            },
-            Err(_) => return Err(Error::from_str("git am failed")),
-        };
+        try!(apply::apply_mail(repo, &file));
     }
     Ok(())
 }
 
-fn remove_patches(repo: &Repository, branch_name: &str) {
-    fs::remove_dir_all(format!("{}/output-{}/", repo.workdir().unwrap().to_str().unwrap_or("./"),
-                               branch_name.replace("/", "_"))).unwrap();
+fn remove_patches(repo: &Repository, branch_name: &str, version: u32) -> Result<(), io::Error> {
+    let dir = patch_dir(repo, branch_name, version);
+    try!(fs::remove_dir_all(&dir));
+    // Clean up the directories of the branch as well, unless another
+    // version is still in progress there.
+    let submit_dir = repo.path().join("submit");
+    let mut parent = dir.parent();
+    while let Some(path) = parent {
+        if !path.starts_with(&submit_dir) || fs::remove_dir(path).is_err() {
+            break;
+        }
+        parent = path.parent();
+    }
+    Ok(())
 }
 
-fn remove_tag(repo: &Repository, branch_name: &str, version: u32) {
-    repo.tag_delete(format!("{}-v{}", branch_name, version).as_str()).unwrap();
+/// Undo what was done so far after something failed.  If that fails
+/// too, it is only reported, so the original error and its exit code
+/// are what the user gets.
+fn cleanup<E: fmt::Display>(result: Result<(), E>) {
+    if let Err(e) = result {
+        writeln!(io::stderr(), "warning: cleaning up failed: {}", e).unwrap();
+    }
 }
 
-fn format_addr(mb: Mailbox) -> String {
-    match mb.name {
-        Some(name) => format!("{} <{}>", name, mb.address),
-        None => mb.address,
+fn remove_tag(repo: &Repository, branch_name: &str, version: u32) -> Result<(), Error> {
+    repo.tag_delete(version::tag_name(branch_name, version).as_str())
+}
+
+/// What was sent for the version before `version`, which the new version
+/// is threaded to when no `--in-reply-to` is given.
+fn previous_version(repo: &Repository, branch_name: &str, version: u32)
+                    -> Result<Option<VersionInfo>, SubmitError> {
+    if version == 1 {
+        return Ok(None);
+    }
+    match try!(VersionInfo::read(repo, branch_name, version - 1)) {
+        Some(ref info) if info.message_ids.is_empty() => (),
+        Some(info) => return Ok(Some(info)),
+        None => (),
     }
+    Err(SubmitError::Usage(format!(
+        "This is version {} of the patch series, but it's not known what v{} was sent as, \
+         --in-reply-to=<previous-message-id> should be used", version, version - 1)))
 }
 
-fn find_addresses(command_line: Vec<String>, reply_to: Option<String>, addr_type: String)
-           -> Result<Vec<String>, hyper::error::Error> {
+fn find_addresses(command_line: Vec<String>, context: Option<&ThreadContext>,
+                  addr_type: &str) -> Vec<String> {
     let mut addresses = command_line;
-    match reply_to {
-        Some(r) => {
-            let client = Client::new();
-            let article_res =
-                try!(client.get(format!("http://mid.gmane.org/{}", r).as_str()).send());
-            let mut raw_res = try!(client.get(
-                format!("{}/raw", article_res.url.serialize()).as_str()).send());
-
-            let mut body = String::new();
-            raw_res.read_to_string(&mut body).unwrap();
-            let header_map = MimeMessage::parse(body.as_str()).unwrap().headers;
-
-            for addr in header_map.get_value::<Vec<Address>>(addr_type).unwrap() {
-                match addr {
-                    Address::Mailbox(mb) => addresses.push(format_addr(mb)),
-                    Address::Group(_, g) => {
-                        for mb in g {
-                            addresses.push(format_addr(mb));
-                        }
-                    },
-                };
-            };
-        },
-        None => (),
-    };
-    Ok(addresses)
+    if let Some(context) = context {
+        match addr_type {
+            "From" => addresses.extend(context.from.iter().cloned()),
+            "To" => addresses.extend(context.to.iter().cloned()),
+            "Cc" => addresses.extend(context.cc.iter().cloned()),
+            _ => (),
+        }
+    }
+    addresses
 }
 
 fn main() {
+    if let Err(e) = submit() {
+        writeln!(io::stderr(), "error: {}", e).unwrap();
+        process::exit(e.exit_code());
+    }
+}
+
+fn submit() -> Result<(), SubmitError> {
     let args: Vec<String> = env::args().collect();
 
     let mut opts = Options::new();
     opts.optmulti("", "to", "set to addresses", "to");
     opts.optmulti("", "cc", "set cc addresses", "cc");
     opts.optopt("", "in-reply-to", "reply to message-id", "message-id");
+    opts.optopt("", "base", "send the commits on top of this revision", "rev");
+    opts.optopt("", "branch", "send this branch, which has to point at HEAD", "branch");
+    opts.optflag("n", "dry-run", "do everything except tagging and sending the series");
+    opts.optopt("", "mbox", "look for the replies in this mbox instead of the archive", "path");
+    opts.optflag("", "continue", "same as the continue command");
+    opts.optflag("", "abort", "same as the abort command");
     opts.optflag("h", "help", "print this help menu");
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
-        Err(e) => panic!("error: {}", e),
+        Err(e) => return Err(SubmitError::Usage(format!("{}", e))),
     };
     if matches.opt_present("h") {
-        print!("{}", opts.usage(&format!("usage: [options]")));
-        return;
+        print!("{}", opts.usage("usage: git submit [<command>] [options]\n\n\
+                                 Commands:\n    \
+                                 submit      format, edit and send the series (the default)\n    \
+                                 status      show the series on the current branch\n    \
+                                 log         list the versions that were sent\n    \
+                                 diff vA vB  show the range-diff between two versions\n    \
+                                 trailers    add the trailers from the replies to the last \
+                                 version\n    \
+                                 continue    continue after resolving a patch that failed \
+                                 to apply\n    \
+                                 abort       abort the submission and restore the branch"));
+        return Ok(());
+    }
+    let repo = try!(Repository::discover("."));
+    let command = if matches.opt_present("continue") {
+        "continue"
+    } else if matches.opt_present("abort") {
+        "abort"
+    } else {
+        matches.free.get(0).map_or("submit", |c| c.as_str())
+    };
+    match command {
+        "submit" => submit_series(&repo, &matches),
+        "continue" => continue_submit(&repo),
+        "abort" => abort_submit(&repo),
+        "trailers" => trailers_command(&repo, &matches),
+        "status" => {
+            let branch_name = try!(branch_name(&repo, matches.opt_str("branch")));
+            print!("{}", try!(status(&repo, branch_name.as_str(), matches.opt_str("base"))));
+            Ok(())
+        },
+        "log" => {
+            let branch_name = try!(branch_name(&repo, matches.opt_str("branch")));
+            print!("{}", try!(log(&repo, branch_name.as_str())));
+            Ok(())
+        },
+        "diff" => {
+            let branch_name = try!(branch_name(&repo, matches.opt_str("branch")));
+            diff_versions(&repo, branch_name.as_str(), &matches.free[1..])
+        },
+        _ => Err(SubmitError::Usage(format!("unknown command '{}'", command))),
+    }
+}
+
+fn submit_series(repo: &Repository, matches: &Matches) -> Result<(), SubmitError> {
+    if try!(State::load(repo)).is_some() {
+        return Err(SubmitError::Usage(String::from(
+            "a submission is in progress, use \"git submit continue\" or \
+             \"git submit abort\"")));
     }
-    let to_only: Vec<String> = find_addresses(matches.opt_strs("to"),
-                                              matches.opt_str("in-reply-to"),
-                                              String::from("To")).unwrap();
+    let branch = try!(current_branch(repo, matches.opt_str("branch")));
+    let branch_name = match try!(branch.name()) {
+        Some(name) => name,
+        None => return Err(SubmitError::from(Error::from_str("branch name not valid"))),
+    };
+    let settings = try!(Settings::new(repo, Some(branch_name)));
+    let archive = try!(archive::from_config(&settings));
+    let context = match matches.opt_str("in-reply-to") {
+        Some(message_id) => Some(try!(ThreadContext::fetch(&*archive, message_id.as_str()))),
+        None => None,
+    };
+    let mut to_only = matches.opt_strs("to");
+    to_only.extend(try!(settings.to()));
+    let to_only: Vec<String> = find_addresses(to_only, context.as_ref(), "To");
     // Add the from address to the to list as well.
-    let to: Vec<String> = find_addresses(to_only, matches.opt_str("in-reply-to"),
-                                         String::from("From")).unwrap();
-    let cc: Vec<String> = find_addresses(matches.opt_strs("cc"),
-                                         matches.opt_str("in-reply-to"),
-                                         String::from("Cc")).unwrap();
-
-    let repo = Repository::discover(".").unwrap();
-    match is_clean(&repo) {
-        Ok(clean) => if !clean {
-            panic!("git-submit can't be run with changes in the working tree");
+    let mut to: Vec<String> = find_addresses(to_only, context.as_ref(), "From");
+    let mut cc_only = matches.opt_strs("cc");
+    cc_only.extend(try!(settings.cc()));
+    let mut cc: Vec<String> = find_addresses(cc_only, context.as_ref(), "Cc");
+
+    if !try!(is_clean(repo)) {
+        return Err(SubmitError::DirtyWorktree);
+    }
+    try!(set_path(repo));
+    let base = try!(find_base(repo, &branch, matches.opt_str("base")));
+    let revs = try!(revs_to_send(repo, branch.get(), base.as_ref().map(|&(oid, _)| oid)));
+    if revs.is_empty() {
+        return Err(SubmitError::Usage(String::from("there are no commits to send")));
+    }
+    try!(check_linear(repo, &revs));
+    match base {
+        Some((oid, ref source)) => println!("Using {} ({}) as the base", oid, source),
+        None => println!("Using {}~ as the base, set --base, an upstream or submit.base to \
+                          override it", revs[revs.len() - 1]),
+    }
+    let version = try!(find_version(repo, branch_name));
+    let mut references = context.as_ref().map_or(Vec::new(), |c| c.references.clone());
+    let mut in_reply_to = context.map(|c| c.message_id);
+    if in_reply_to.is_none() {
+        if let Some(previous) = try!(previous_version(repo, branch_name, version)) {
+            println!("Replying to {} from v{}", previous.message_ids[0], version - 1);
+            in_reply_to = Some(previous.message_ids[0].clone());
+            references.extend(previous.in_reply_to);
+            to.extend(previous.to);
+            cc.extend(previous.cc);
+        }
+    }
+    let (to, cc) = address::dedup(to, cc, &try!(settings.suppressed()));
+    let version_diff = try!(version_diff(repo, branch_name, version, &revs));
+    let prepared = format_patches(repo, &revs, branch_name, version, version_diff, &settings)
+        .and_then(|_| prefill_cover_letter(repo, branch_name, version))
+        .and_then(|_| edit_patches(repo, branch_name, version, &settings));
+    if let Err(e) = prepared {
+        // Formatting may have failed before there was anything to clean up.
+        if patch_dir(repo, branch_name, version).exists() {
+            cleanup(remove_patches(repo, branch_name, version));
+        }
+        return Err(e);
+    }
+    if !matches.opt_present("dry-run") {
+        if let Err(e) = check_cover_letter(repo, branch_name, version) {
+            cleanup(remove_patches(repo, branch_name, version));
+            return Err(e);
+        }
+    }
+    let head = try!(try!(repo.head()).peel(ObjectType::Any));
+    let mut state = State {
+        branch: String::from(branch_name),
+        head: head.id(),
+        version: version,
+        base: try!(repo.find_commit(revs[revs.len() - 1])).parent_id(0).ok(),
+        failed: String::new(),
+        in_reply_to: in_reply_to,
+        references: references,
+        to: to,
+        cc: cc,
+        dry_run: matches.opt_present("dry-run"),
+    };
+    if let Err(e) = rebuild_branch(repo, &revs, branch_name, version) {
+        if let SubmitError::ApplyPatch(ref patch) = e {
+            state.failed = patch.clone();
+            try!(state.save(repo));
+            println!("Resolve the conflict and run \"git submit continue\", or run \
+                      \"git submit abort\" to restore the branch.");
+        } else {
+            cleanup(repo.reset(&head, ResetType::Hard, Some(&mut CheckoutBuilder::new())));
+            cleanup(remove_patches(repo, branch_name, version));
+        }
+        return Err(e);
+    };
+    finish_submit(repo, state)
+}
+
+/// Tag the re-built branch and send the series.  The tag records what
+/// is about to be sent, and is removed again if sending fails.
+fn finish_submit(repo: &Repository, mut state: State) -> Result<(), SubmitError> {
+    let branch_name = state.branch.as_str();
+    let version = state.version;
+    let settings = try!(Settings::new(repo, Some(branch_name)));
+    let files = try!(patch_files(repo, branch_name, version));
+    let cover = try!(cover_letter(repo, branch_name, version));
+    let cc_cmd = settings.cc_cmd();
+    try!(transport::assign_cc(&files, cover.as_ref(), cc_cmd.as_ref().map(|c| c.as_str()),
+                              &try!(settings.suppressed())));
+    if state.dry_run {
+        try!(print_dry_run(repo, branch_name, version, &state.in_reply_to, &state.to,
+                           &state.cc));
+        try!(remove_patches(repo, branch_name, version));
+        return Ok(());
+    }
+    if settings.review_recipients() {
+        match review_recipients(repo, &files, &state.to, &state.cc) {
+            Ok((to, cc)) => {
+                state.to = to;
+                state.cc = cc;
+            },
+            Err(e) => {
+                cleanup(remove_patches(repo, branch_name, version));
+                return Err(e);
+            },
+        }
+    }
+    let config = try!(repo.config());
+    let from = try!(transport::sender(&config));
+    let (cover_subject, cover_blurb) = match cover {
+        Some(path) => {
+            let mut contents = String::new();
+            try!(try!(File::open(path)).read_to_string(&mut contents));
+            cover::parse(contents.as_str())
         },
-        Err(e) => panic!("error: {}", e),
-    }
-    set_path(&repo);
-    let revs = revs_to_send(&repo).unwrap();
-    let branch = current_branch(&repo).unwrap();
-    let branch_name = match branch.name() {
-        Ok(None) => panic!("branch name not valid"),
-        Ok(Some(name)) => name,
-        Err(e) => panic!("error: {}", e),
+        None => (None, None),
+    };
+    let info = VersionInfo {
+        base: state.base,
+        in_reply_to: state.in_reply_to.clone(),
+        to: state.to.clone(),
+        cc: state.cc.clone(),
+        message_ids: try!(transport::assign_message_ids(&files, from.as_str())),
+        sent: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+        cover_subject: cover_subject,
+        cover_blurb: cover_blurb,
+    };
+    if let Err(e) = tag_version(repo, branch_name, version, &info) {
+        cleanup(remove_patches(repo, branch_name, version));
+        return Err(SubmitError::from(e));
     };
-    let version = find_version(&repo, branch_name).unwrap();
-    if version > 1 && matches.opt_str("in-reply-to").is_none() {
-        panic!("This is version {} of the patch series, --in-reply-to=<previous-message-id> should be used",
-               version);
-    }
-    format_patches(&revs, branch_name, version);
-    edit_patches(&repo, branch_name).unwrap();
-    let head = repo.head().unwrap();
-    if let Err(e) = rebuild_branch(&repo, &revs, branch_name) {
-        repo.reset(&head.peel(ObjectType::Any).unwrap(), ResetType::Hard,
-                   Some(&mut CheckoutBuilder::new())).unwrap();
-        remove_patches(&repo, branch_name);
-        panic!("error: {}", e);
+    let result = match try!(transport::from_config(&config)) {
+        Some(mut transport) => transport::send_patches(&mut *transport, &files, from.as_str(),
+                                                       &state.to, &state.cc, state.in_reply_to,
+                                                       &state.references),
+        None => send_emails(repo, branch_name, version, state.in_reply_to, state.to, state.cc),
+    };
+    if let Err(e) = result {
+        cleanup(remove_tag(repo, branch_name, version));
+        return Err(e);
+    };
+    try!(remove_patches(repo, branch_name, version));
+    Ok(())
+}
+
+fn continue_submit(repo: &Repository) -> Result<(), SubmitError> {
+    let mut state = match try!(State::load(repo)) {
+        Some(state) => state,
+        None => return Err(SubmitError::Usage(String::from("no submission in progress"))),
+    };
+    try!(set_path(repo));
+    // The patch that failed is committed as it was resolved.
+    try!(apply::commit_resolved(repo, Path::new(state.failed.as_str())));
+    if let Err(e) = apply_patches(repo, state.branch.as_str(), state.version,
+                                  Some(state.failed.as_str())) {
+        if let SubmitError::ApplyPatch(ref patch) = e {
+            state.failed = patch.clone();
+            try!(state.save(repo));
+        }
+        return Err(e);
+    }
+    try!(State::remove(repo));
+    finish_submit(repo, state)
+}
+
+fn abort_submit(repo: &Repository) -> Result<(), SubmitError> {
+    let state = match try!(State::load(repo)) {
+        Some(state) => state,
+        None => return Err(SubmitError::Usage(String::from("no submission in progress"))),
     };
-    if let Err(e) = tag_version(&repo, branch_name, version) {
-        remove_patches(&repo, branch_name);
-        panic!("error: {}", e);
+    try!(set_path(repo));
+    let head = try!(repo.find_object(state.head, None));
+    try!(repo.reset(&head, ResetType::Hard, Some(&mut CheckoutBuilder::new())));
+    try!(remove_patches(repo, state.branch.as_str(), state.version));
+    State::remove(repo)
+}
+
+fn trailers_command(repo: &Repository, matches: &Matches) -> Result<(), SubmitError> {
+    if try!(State::load(repo)).is_some() {
+        return Err(SubmitError::Usage(String::from(
+            "a submission is in progress, use \"git submit continue\" or \
+             \"git submit abort\"")));
+    }
+    let branch = try!(current_branch(repo, matches.opt_str("branch")));
+    let branch_name = match try!(branch.name()) {
+        Some(name) => name,
+        None => return Err(SubmitError::from(Error::from_str("branch name not valid"))),
     };
-    if let Err(e) = send_emails(&repo, branch_name, matches.opt_str("in-reply-to"), to, cc) {
-        remove_tag(&repo, branch_name, version);
-        panic!("error: {}", e);
+    let settings = try!(Settings::new(repo, Some(branch_name)));
+    let archive: Box<Archive> = match matches.opt_str("mbox") {
+        Some(path) => Box::new(archive::Mbox::new(Path::new(path.as_str()))),
+        None => try!(archive::from_config(&settings)),
     };
-    remove_patches(&repo, branch_name);
+    if !try!(is_clean(repo)) {
+        return Err(SubmitError::DirtyWorktree);
+    }
+    try!(set_path(repo));
+    match try!(add_trailers(repo, branch_name, &*archive, &settings)) {
+        0 => println!("No new trailers in the replies"),
+        added => println!("Added {} trailer(s)", added),
+    }
+    Ok(())
+}
+
+/// Add the `Reviewed-by`, `Acked-by` and `Tested-by` trailers from the
+/// replies to the last version that was sent to the commits on the
+/// branch.  Commits are matched by their subject, the trailers given
+/// for the cover letter, in the replies to it that aren't below one of
+/// the patches, go to all of them.  The branch is re-built from
+/// patches, like for a submission, and the number of trailers added is
+/// returned.
+fn add_trailers(repo: &Repository, branch_name: &str, archive: &Archive, settings: &Settings)
+                -> Result<usize, SubmitError> {
+    let version = try!(find_version(repo, branch_name)) - 1;
+    let info = match version {
+        0 => None,
+        _ => try!(VersionInfo::read(repo, branch_name, version)),
+    };
+    let (base, message_ids) = match info {
+        Some(info) => (info.base, info.message_ids),
+        None => return Err(SubmitError::Usage(format!(
+            "it's not known what the last version of {} was sent as", branch_name))),
+    };
+    let branch = try!(repo.find_branch(branch_name, BranchType::Local));
+    // The series is rebuilt on the base it was sent with, which only tags
+    // that don't record it have to guess.
+    let base = match base {
+        Some(base) => Some(base),
+        None => try!(find_base(repo, &branch, None)).map(|b| b.0),
+    };
+
+    let tag = try!(try!(repo.revparse_single(version::tag_name(branch_name, version).as_str()))
+                   .peel(ObjectType::Commit)).id();
+    let mut revwalk = try!(repo.revwalk());
+    revwalk.set_sorting(git2::SORT_TOPOLOGICAL | git2::SORT_REVERSE);
+    try!(revwalk.push(tag));
+    if let Some(base) = base {
+        try!(revwalk.hide(base));
+    }
+    let sent = revwalk.collect::<Vec<_>>();
+    let has_cover = message_ids.len() > sent.len();
+    let cover_trailers = if has_cover {
+        try!(trailers::collect(archive, message_ids[0].as_str(), &message_ids[1..]))
+    } else {
+        Vec::new()
+    };
+    let mut found = Vec::new();
+    for (rev, message_id) in sent.iter().zip(message_ids[has_cover as usize..].iter()) {
+        let mut patch_trailers = cover_trailers.clone();
+        for trailer in try!(trailers::collect(archive, message_id.as_str(), &[])) {
+            if !patch_trailers.contains(&trailer) {
+                patch_trailers.push(trailer);
+            }
+        }
+        let mut commit = try!(repo.find_commit(*rev));
+        found.push((String::from(commit.summary().unwrap_or("")), patch_trailers));
+    }
+
+    let revs = try!(revs_to_send(repo, branch.get(), base));
+    try!(check_linear(repo, &revs));
+    let next = version + 1;
+    try!(format_patches(repo, &revs, branch_name, next, None, settings));
+    let cover = try!(cover_letter(repo, branch_name, next));
+    let files = try!(patch_files(repo, branch_name, next)).into_iter()
+        .filter(|f| Some(f) != cover.as_ref())
+        .collect::<Vec<_>>();
+    let mut added = 0;
+    for (file, rev) in files.iter().zip(revs.iter().rev()) {
+        let mut commit = try!(repo.find_commit(*rev));
+        let message = String::from(commit.message().unwrap_or(""));
+        let summary = String::from(commit.summary().unwrap_or(""));
+        let new = match found.iter().find(|f| f.0 == summary) {
+            Some(&(_, ref trailers)) => trailers.iter()
+                .filter(|t| !message.lines().any(|l| l.trim() == t.as_str()))
+                .cloned()
+                .collect::<Vec<_>>(),
+            None => continue,
+        };
+        for trailer in new.iter() {
+            println!("{}: {}", summary, trailer);
+        }
+        added += new.len();
+        let mut contents = Vec::new();
+        try!(try!(File::open(file)).read_to_end(&mut contents));
+        let contents = trailers::add_to_patch(&contents, &new);
+        try!(try!(File::create(file)).write_all(&contents));
+    }
+
+    let mut result = Ok(added);
+    if added > 0 {
+        let head = try!(try!(repo.head()).peel(ObjectType::Any));
+        if let Err(e) = rebuild_branch(repo, &revs, branch_name, next) {
+            cleanup(repo.reset(&head, ResetType::Hard, Some(&mut CheckoutBuilder::new())));
+            result = Err(e);
+        }
+    }
+    match result {
+        Ok(_) => try!(remove_patches(repo, branch_name, next)),
+        Err(_) => cleanup(remove_patches(repo, branch_name, next)),
+    }
+    result
+}
+
+/// The branch the read-only commands look at, the one given with
+/// `--branch` or the current one.
+fn branch_name(repo: &Repository, name: Option<String>) -> Result<String, SubmitError> {
+    if let Some(name) = name {
+        return Ok(name);
+    }
+    let branch = try!(current_branch(repo, None));
+    let name = try!(branch.name());
+    Ok(String::from(try!(name.ok_or(Error::from_str("branch name not valid")))))
+}
+
+/// A summary of the series on a branch, for `git submit status`.
+fn status(repo: &Repository, branch_name: &str, base: Option<String>)
+          -> Result<String, SubmitError> {
+    let branch = try!(repo.find_branch(branch_name, BranchType::Local));
+    let mut status = format!("On branch {}\n", branch_name);
+    let base = try!(find_base(repo, &branch, base));
+    let revs = try!(revs_to_send(repo, branch.get(), base.as_ref().map(|&(oid, _)| oid)));
+    match base {
+        Some((oid, ref source)) =>
+            status.push_str(format!("Base: {} ({})\n", oid, source).as_str()),
+        None => if let Some(last) = revs.last() {
+            status.push_str(format!("Base: {}~ (guessed)\n", last).as_str());
+        },
+    }
+    let version = try!(find_version(repo, branch_name));
+    status.push_str(format!("{} commit(s) to send as v{}\n", revs.len(), version).as_str());
+    if version == 1 {
+        status.push_str("Not sent yet\n");
+    } else {
+        let previous = version - 1;
+        match try!(VersionInfo::read(repo, branch_name, previous)) {
+            Some(info) => status.push_str(format!(
+                "v{} was sent on {} to {} recipient(s) as {}\n", previous,
+                version::format_time(info.sent), info.to.len() + info.cc.len(),
+                info.message_ids.first().map_or("an unknown message", |id| id.as_str()))
+                                          .as_str()),
+            None => status.push_str(format!("v{} was sent\n", previous).as_str()),
+        }
+        let tag = try!(repo.revparse_single(version::tag_name(branch_name, previous).as_str()));
+        if Some(try!(tag.peel(ObjectType::Commit)).id()) == branch.get().target() {
+            status.push_str(format!("The branch has not changed since v{}\n", previous).as_str());
+        } else {
+            status.push_str(format!("The branch has changed since v{}\n", previous).as_str());
+        }
+    }
+    if try!(State::load(repo)).is_some() {
+        status.push_str("A submission is in progress, use \"git submit continue\" or \
+                         \"git submit abort\"\n");
+    }
+    Ok(status)
+}
+
+/// One line for every version that was sent, for `git submit log`.
+fn log(repo: &Repository, branch_name: &str) -> Result<String, SubmitError> {
+    let mut log = String::new();
+    for version in 1..try!(find_version(repo, branch_name)) {
+        let commit = match repo.revparse_single(version::tag_name(branch_name, version).as_str()) {
+            Ok(tag) => try!(tag.peel(ObjectType::Commit)).id().to_string(),
+            Err(_) => continue,
+        };
+        let line = match try!(VersionInfo::read(repo, branch_name, version)) {
+            Some(info) => format!("v{} {} {} {}\n", version, &commit[..7],
+                                  version::format_time(info.sent),
+                                  info.cover_subject.or(info.message_ids.into_iter().next())
+                                  .unwrap_or(String::new())),
+            None => format!("v{} {}\n", version, &commit[..7]),
+        };
+        log.push_str(line.as_str());
+    }
+    Ok(log)
+}
+
+/// Show the range-diff between two versions, for `git submit diff`.
+fn diff_versions(repo: &Repository, branch_name: &str, args: &[String])
+                 -> Result<(), SubmitError> {
+    if args.len() != 2 {
+        return Err(SubmitError::Usage(String::from("usage: git submit diff <vA> <vB>")));
+    }
+    let mut ranges = Vec::new();
+    for arg in args {
+        let version = match arg.trim_left_matches('v').parse::<u32>() {
+            Ok(version) => version,
+            Err(_) => return Err(SubmitError::Usage(format!("'{}' is not a version", arg))),
+        };
+        let tag = version::tag_name(branch_name, version);
+        try!(repo.revparse_single(tag.as_str()));
+        let base = try!(VersionInfo::read(repo, branch_name, version)).and_then(|i| i.base);
+        ranges.push((tag, base));
+    }
+    let mut command = Command::new("git");
+    command.arg("range-diff");
+    match (&ranges[0], &ranges[1]) {
+        (&(ref tag_a, Some(base_a)), &(ref tag_b, Some(base_b))) => {
+            command.arg(format!("{}..{}", base_a, tag_a));
+            command.arg(format!("{}..{}", base_b, tag_b));
+        },
+        (&(ref tag_a, _), &(ref tag_b, _)) => {
+            command.arg(format!("{}...{}", tag_a, tag_b));
+        },
+    }
+    let status = try!(command.status());
+    if !status.success() {
+        return Err(SubmitError::from(Error::from_str("git range-diff failed")));
+    }
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{branches, current_branch, edit_patches, find_addresses, find_version,
-                format_addr, format_patches, remove_patches, remove_tag, revs_to_send,
-                set_path, tag_version};
-
-    use email::Mailbox;
+    use super::{add_trailers, branches, check_cover_letter, check_linear, cover_letter,
+                current_branch, diff_versions, edit_patches, find_addresses, find_base,
+                find_version, format_patches, log, patch_dir, patch_files, previous_version,
+                rebuild_branch, remove_patches, remove_tag, review_recipients, revs_to_send,
+                set_path, status, tag_version, version_diff};
+
+    use archive::{Gmane, Mbox, PublicInbox};
+    use config::Settings;
+    use archive::tests::{MESSAGE, MESSAGE_ID, serve_message};
+    use error::SubmitError;
     use git2::{Error, Repository, Signature, Tree};
     use std::env;
     use std::fs::{self, File};
-    use std::io::{self, Write};
+    use std::io::{self, Read, Write};
     use std::path::Path;
+    use std::sync::atomic::{ATOMIC_BOOL_INIT, AtomicBool, Ordering};
     use tempdir::TempDir;
+    use thread::ThreadContext;
+    use version::VersionInfo;
+
+    // EDITOR is shared by all tests, only one of them may set it at a time.
+    static EDITOR_IN_USE: AtomicBool = ATOMIC_BOOL_INIT;
+
+    struct EditorLock;
+
+    impl Drop for EditorLock {
+        fn drop(&mut self) {
+            EDITOR_IN_USE.store(false, Ordering::SeqCst);
+        }
+    }
+
+    fn lock_editor() -> EditorLock {
+        while EDITOR_IN_USE.compare_and_swap(false, true, Ordering::SeqCst) {
+            ::std::thread::yield_now();
+        }
+        EditorLock
+    }
 
     fn init_test_repo(path: &str) -> Result<(), Error> {
         let repo = try!(Repository::init(path));
-        set_path(&repo);
+        set_path(&repo).unwrap();
+        let mut config = try!(repo.config());
+        try!(config.set_str("user.name", "A U Thor"));
+        try!(config.set_str("user.email", "author@example.net"));
 
         let sig = Signature::now("A U Thor", "author@example.net").unwrap();
 
@@ -373,6 +1159,10 @@ mod tests {
         Ok(())
     }
 
+    fn settings(repo: &Repository) -> Settings {
+        Settings::new(repo, Some("master")).unwrap()
+    }
+
     fn write_file(file: &Path) -> Result<(), io::Error> {
         let mut f = try!(File::create(file));
         try!(f.write_all(b"Hello it's me!"));
@@ -400,7 +1190,7 @@ mod tests {
         init_test_repo(repo_path).unwrap();
         let repo = Repository::open(repo_path).unwrap();
 
-        let bs = branches(&repo).unwrap();
+        let bs = branches(&repo, &repo.head().unwrap()).unwrap();
         assert_eq!(bs.len(), 1);
         assert!(bs[0].is_branch());
         assert_eq!(bs[0].name(), Some("refs/heads/test"));
@@ -415,9 +1205,78 @@ mod tests {
         init_test_repo(repo_path).unwrap();
         let repo = Repository::open(repo_path).unwrap();
 
-        let revs = revs_to_send(&repo).unwrap();
+        let revs = revs_to_send(&repo, &repo.head().unwrap(), None).unwrap();
         assert_eq!(revs.len(), 2);
 
+        let commit1 = repo.revparse_single("HEAD~2").unwrap().id();
+        let base = repo.revparse_single("HEAD~").unwrap().id();
+        assert_eq!(revs_to_send(&repo, &repo.head().unwrap(), Some(base)).unwrap().len(), 1);
+        assert_eq!(revs_to_send(&repo, &repo.head().unwrap(), Some(commit1)).unwrap().len(), 2);
+
+        fs::remove_dir_all(repo_path).unwrap();
+    }
+
+    #[test]
+    fn test_check_linear() {
+        let tempdir = Box::new(TempDir::new("git-submit").unwrap());
+        let repo_path = tempdir.path().to_str().unwrap();
+        init_test_repo(repo_path).unwrap();
+        let repo = Repository::open(repo_path).unwrap();
+        let revs = revs_to_send(&repo, &repo.head().unwrap(), None).unwrap();
+        check_linear(&repo, &revs).unwrap();
+
+        let sig = Signature::now("A U Thor", "author@example.net").unwrap();
+        let head = repo.find_commit(repo.head().unwrap().target().unwrap()).unwrap();
+        let test = repo.find_commit(repo.revparse_single("test").unwrap().id()).unwrap();
+        let side_oid = repo.commit(Some("refs/heads/test"), &sig, &sig, "side",
+                                   &test.tree().unwrap(), &[&test]).unwrap();
+        let side = repo.find_commit(side_oid).unwrap();
+        let merge = repo.commit(Some("HEAD"), &sig, &sig, "Merge branch 'test'",
+                                &head.tree().unwrap(), &[&head, &side]).unwrap();
+
+        let base = repo.revparse_single("HEAD~3").unwrap().id();
+        let revs = revs_to_send(&repo, &repo.head().unwrap(), Some(base)).unwrap();
+        assert_eq!(revs.len(), 4);
+        assert_eq!(revs[0], merge);
+        match check_linear(&repo, &revs) {
+            Err(SubmitError::Usage(msg)) => {
+                assert!(msg.contains(format!("{} Merge branch 'test'", merge).as_str()));
+                assert!(!msg.contains(format!("{}", side_oid).as_str()));
+            },
+            _ => panic!("merge not detected"),
+        }
+
+        fs::remove_dir_all(repo_path).unwrap();
+    }
+
+    #[test]
+    fn test_find_base() {
+        let tempdir = Box::new(TempDir::new("git-submit").unwrap());
+        let repo_path = tempdir.path().to_str().unwrap();
+        init_test_repo(repo_path).unwrap();
+        let repo = Repository::open(repo_path).unwrap();
+        let commit1 = repo.revparse_single("HEAD~2").unwrap().id();
+        let commit2 = repo.revparse_single("HEAD~").unwrap().id();
+
+        let mut branch = current_branch(&repo, None).unwrap();
+        assert!(find_base(&repo, &branch, None).unwrap().is_none());
+
+        repo.config().unwrap().set_str("submit.base", "HEAD~").unwrap();
+        assert_eq!(find_base(&repo, &branch, None).unwrap().unwrap(),
+                   (commit2, String::from("HEAD~ from submit.base")));
+        repo.config().unwrap().set_str("branch.master.submitBase", "HEAD~2").unwrap();
+        assert_eq!(find_base(&repo, &branch, None).unwrap().unwrap(),
+                   (commit1, String::from("HEAD~2 from branch.master.submitBase")));
+
+        branch.set_upstream(Some("test")).unwrap();
+        let (base, source) = find_base(&repo, &branch, None).unwrap().unwrap();
+        assert_eq!(base, commit1);
+        assert_eq!(source, "test from the upstream test");
+
+        let base = find_base(&repo, &branch, Some(String::from("HEAD"))).unwrap().unwrap();
+        assert_eq!(base.0, repo.head().unwrap().target().unwrap());
+        assert!(find_base(&repo, &branch, Some(String::from("missing"))).is_err());
+
         fs::remove_dir_all(repo_path).unwrap();
     }
 
@@ -428,7 +1287,7 @@ mod tests {
         init_test_repo(repo_path).unwrap();
         let repo = Repository::open(repo_path).unwrap();
 
-        let branch = current_branch(&repo).unwrap();
+        let branch = current_branch(&repo, None).unwrap();
 
         let branch_name = branch.name().unwrap();
         assert_eq!(branch_name, Some("master"));
@@ -436,19 +1295,118 @@ mod tests {
         fs::remove_dir_all(repo_path).unwrap();
     }
 
+    #[test]
+    fn test_current_branch_explicit() {
+        let tempdir = Box::new(TempDir::new("git-submit").unwrap());
+        let repo_path = tempdir.path().to_str().unwrap();
+        init_test_repo(repo_path).unwrap();
+        let repo = Repository::open(repo_path).unwrap();
+        let head = repo.head().unwrap().target().unwrap();
+        repo.branch("other", &repo.find_commit(head).unwrap(), false).unwrap();
+
+        assert_eq!(current_branch(&repo, None).unwrap().name().unwrap(), Some("master"));
+        let branch = current_branch(&repo, Some(String::from("other"))).unwrap();
+        assert_eq!(branch.name().unwrap(), Some("other"));
+        assert_eq!(repo.head().unwrap().name(), Some("refs/heads/other"));
+        assert!(current_branch(&repo, Some(String::from("test"))).is_err());
+
+        repo.set_head_detached(head).unwrap();
+        assert!(current_branch(&repo, None).is_err());
+        let branch = current_branch(&repo, Some(String::from("master"))).unwrap();
+        assert_eq!(branch.name().unwrap(), Some("master"));
+        assert!(repo.head().unwrap().is_branch());
+
+        fs::remove_dir_all(repo_path).unwrap();
+    }
+
     #[test]
     fn test_format_patches() {
         let tempdir = Box::new(TempDir::new("git-submit").unwrap());
         let repo_path = tempdir.path().to_str().unwrap();
         init_test_repo(repo_path).unwrap();
         let repo = Repository::open(repo_path).unwrap();
-        set_path(&repo);
+        set_path(&repo).unwrap();
 
-        let revs = revs_to_send(&repo).unwrap();
-        format_patches(&revs, "master", 1);
+        let revs = revs_to_send(&repo, &repo.head().unwrap(), None).unwrap();
+        format_patches(&repo, &revs, "master", 1, None, &settings(&repo)).unwrap();
 
-        let patch_files = fs::read_dir(format!("{}/output-master", repo_path)).unwrap();
+        let patch_files = fs::read_dir(patch_dir(&repo, "master", 1)).unwrap();
         assert_eq!(patch_files.count(), 2);
+        remove_patches(&repo, "master", 1).unwrap();
+
+        let mut config = repo.config().unwrap();
+        config.set_i32("branch.master.submitCoverLetterThreshold", 2).unwrap();
+        config.set_str("submit.subjectPrefix", "RFC PATCH").unwrap();
+        format_patches(&repo, &revs, "master", 1, None, &settings(&repo)).unwrap();
+        let files = patch_files(&repo, "master", 1).unwrap();
+        assert_eq!(files.len(), 3);
+        let mut contents = String::new();
+        File::open(&files[1]).unwrap().read_to_string(&mut contents).unwrap();
+        assert!(contents.contains("Subject: [RFC PATCH 1/2] commit 2\n"));
+
+        fs::remove_dir_all(repo_path).unwrap();
+    }
+
+    #[test]
+    fn test_version_diff() {
+        let tempdir = Box::new(TempDir::new("git-submit").unwrap());
+        let repo_path = tempdir.path().to_str().unwrap();
+        init_test_repo(repo_path).unwrap();
+        let repo = Repository::open(repo_path).unwrap();
+        set_path(&repo).unwrap();
+        let base = repo.revparse_single("HEAD~2").unwrap().id();
+
+        let revs = revs_to_send(&repo, &repo.head().unwrap(), Some(base)).unwrap();
+        assert_eq!(version_diff(&repo, "master", 1, &revs).unwrap(), None);
+        let mut info = VersionInfo::parse("");
+        info.base = Some(base);
+        tag_version(&repo, "master", 1, &info).unwrap();
+        let range_diff = version_diff(&repo, "master", 2, &revs).unwrap();
+        let range_diff_text = range_diff.clone().unwrap();
+        assert!(range_diff_text.starts_with("Range-diff against v1:\n1:  "));
+        assert!(range_diff_text.contains(" = 2:  "));
+
+        format_patches(&repo, &revs, "master", 2, range_diff, &settings(&repo)).unwrap();
+        let files = patch_files(&repo, "master", 2).unwrap();
+        assert_eq!(files.len(), 3);
+        assert_eq!(cover_letter(&repo, "master", 2).unwrap().as_ref(), Some(&files[0]));
+        let mut contents = String::new();
+        File::open(&files[0]).unwrap().read_to_string(&mut contents).unwrap();
+        assert!(contents.contains("Range-diff against v1:"));
+        assert!(check_cover_letter(&repo, "master", 2).is_err());
+        let contents = contents.replace("*** SUBJECT HERE ***", "two commits")
+            .replace("*** BLURB HERE ***", "They are needed.");
+        File::create(&files[0]).unwrap().write_all(contents.as_bytes()).unwrap();
+        check_cover_letter(&repo, "master", 2).unwrap();
+
+        let mut config = repo.config().unwrap();
+        config.set_str("submit.versionDiff", "interdiff").unwrap();
+        assert_eq!(version_diff(&repo, "master", 2, &revs).unwrap(),
+                   Some(String::from("Interdiff against v1:\n")));
+        config.set_str("submit.versionDiff", "none").unwrap();
+        assert_eq!(version_diff(&repo, "master", 2, &revs).unwrap(), None);
+        config.set_str("submit.versionDiff", "wdiff").unwrap();
+        assert!(version_diff(&repo, "master", 2, &revs).is_err());
+
+        fs::remove_dir_all(repo_path).unwrap();
+    }
+
+    #[test]
+    fn test_patch_files() {
+        let tempdir = Box::new(TempDir::new("git-submit").unwrap());
+        let repo_path = tempdir.path().to_str().unwrap();
+        init_test_repo(repo_path).unwrap();
+        let repo = Repository::open(repo_path).unwrap();
+        set_path(&repo).unwrap();
+
+        let revs = revs_to_send(&repo, &repo.head().unwrap(), None).unwrap();
+        format_patches(&repo, &revs, "master", 1, None, &settings(&repo)).unwrap();
+
+        let files = patch_files(&repo, "master", 1).unwrap();
+        assert_eq!(files.len(), 2);
+        assert!(files[0].ends_with("0001-commit-2.patch"));
+        assert!(files[1].ends_with("0002-commit-3.patch"));
+        assert!(files[0].starts_with(repo.path().join("submit").join("master").join("v1")));
 
         fs::remove_dir_all(repo_path).unwrap();
     }
@@ -476,10 +1434,111 @@ mod tests {
         init_test_repo(repo_path).unwrap();
         let repo = Repository::open(repo_path).unwrap();
 
-        tag_version(&repo, "master", 1).unwrap();
+        let info = VersionInfo {
+            base: Some(repo.revparse_single("HEAD~2").unwrap().id()),
+            in_reply_to: None,
+            to: vec!(String::from("list@example.com")),
+            cc: Vec::new(),
+            message_ids: vec!(String::from("1@example.com"), String::from("2@example.com")),
+            sent: 1453136238,
+            cover_subject: Some(String::from("send things")),
+            cover_blurb: None,
+        };
+        tag_version(&repo, "master", 1, &info).unwrap();
         let tag = repo.find_reference("refs/tags/master-v1").unwrap();
         assert!(tag.is_tag());
         assert_eq!(find_version(&repo, "master").unwrap(), 2);
+        assert_eq!(VersionInfo::read(&repo, "master", 1).unwrap(), Some(info));
+
+        fs::remove_dir_all(repo_path).unwrap();
+    }
+
+    #[test]
+    fn test_previous_version() {
+        let tempdir = Box::new(TempDir::new("git-submit").unwrap());
+        let repo_path = tempdir.path().to_str().unwrap();
+        init_test_repo(repo_path).unwrap();
+        let repo = Repository::open(repo_path).unwrap();
+
+        assert!(previous_version(&repo, "master", 1).unwrap().is_none());
+        let master = repo.revparse_single("master").unwrap();
+        repo.tag_lightweight("master-v1", &master, false).unwrap();
+        assert!(previous_version(&repo, "master", 2).is_err());
+
+        let mut info = VersionInfo::parse("");
+        info.to.push(String::from("list@example.com"));
+        info.message_ids.push(String::from("cover-v2@example.com"));
+        tag_version(&repo, "master", 2, &info).unwrap();
+        assert_eq!(previous_version(&repo, "master", 3).unwrap(), Some(info));
+
+        fs::remove_dir_all(repo_path).unwrap();
+    }
+
+    #[test]
+    fn test_status() {
+        let tempdir = Box::new(TempDir::new("git-submit").unwrap());
+        let repo_path = tempdir.path().to_str().unwrap();
+        init_test_repo(repo_path).unwrap();
+        let repo = Repository::open(repo_path).unwrap();
+
+        let output = status(&repo, "master", None).unwrap();
+        assert!(output.starts_with("On branch master\nBase: "));
+        assert!(output.contains("2 commit(s) to send as v1\nNot sent yet\n"));
+        let commit1 = repo.revparse_single("test").unwrap().id();
+        assert!(status(&repo, "test", None).unwrap().starts_with(
+            format!("On branch test\nBase: {}~ (guessed)\n1 commit(s) to send as v1\n", commit1)
+                .as_str()));
+
+        let mut info = VersionInfo::parse("");
+        info.to.push(String::from("list@example.com"));
+        info.message_ids.push(String::from("1@example.com"));
+        info.sent = 1453136238;
+        tag_version(&repo, "master", 1, &info).unwrap();
+        let output = status(&repo, "master", Some(String::from("HEAD~"))).unwrap();
+        assert!(output.contains("1 commit(s) to send as v2\n\
+                                 v1 was sent on 2016-01-18 16:57 UTC to 1 recipient(s) as \
+                                 1@example.com\nThe branch has not changed since v1\n"));
+
+        fs::remove_dir_all(repo_path).unwrap();
+    }
+
+    #[test]
+    fn test_log() {
+        let tempdir = Box::new(TempDir::new("git-submit").unwrap());
+        let repo_path = tempdir.path().to_str().unwrap();
+        init_test_repo(repo_path).unwrap();
+        let repo = Repository::open(repo_path).unwrap();
+        assert_eq!(log(&repo, "master").unwrap(), "");
+
+        let master = repo.revparse_single("master").unwrap();
+        let short = master.id().to_string()[..7].to_owned();
+        repo.tag_lightweight("master-v1", &master, false).unwrap();
+        let mut info = VersionInfo::parse("");
+        info.message_ids.push(String::from("1@example.com"));
+        info.sent = 1453136238;
+        tag_version(&repo, "master", 2, &info).unwrap();
+        info.cover_subject = Some(String::from("send things"));
+        tag_version(&repo, "master", 3, &info).unwrap();
+        assert_eq!(log(&repo, "master").unwrap(),
+                   format!("v1 {0}\nv2 {0} 2016-01-18 16:57 UTC 1@example.com\n\
+                            v3 {0} 2016-01-18 16:57 UTC send things\n", short));
+
+        fs::remove_dir_all(repo_path).unwrap();
+    }
+
+    #[test]
+    fn test_diff_versions_usage() {
+        let tempdir = Box::new(TempDir::new("git-submit").unwrap());
+        let repo_path = tempdir.path().to_str().unwrap();
+        init_test_repo(repo_path).unwrap();
+        let repo = Repository::open(repo_path).unwrap();
+        tag_version(&repo, "master", 1, &VersionInfo::parse("")).unwrap();
+
+        assert!(diff_versions(&repo, "master", &[String::from("v1")]).is_err());
+        assert!(diff_versions(&repo, "master", &[String::from("v1"), String::from("vx")])
+                .is_err());
+        assert!(diff_versions(&repo, "master", &[String::from("v1"), String::from("v2")])
+                .is_err());
 
         fs::remove_dir_all(repo_path).unwrap();
     }
@@ -490,13 +1549,20 @@ mod tests {
         let repo_path = tempdir.path().to_str().unwrap();
         init_test_repo(repo_path).unwrap();
         let repo = Repository::open(repo_path).unwrap();
-        set_path(&repo);
+        set_path(&repo).unwrap();
 
-        let revs = revs_to_send(&repo).unwrap();
-        format_patches(&revs, "master", 1);
-        remove_patches(&repo, "master");
-        let files = fs::read_dir(format!("{}/output-master", repo_path));
+        let revs = revs_to_send(&repo, &repo.head().unwrap(), None).unwrap();
+        format_patches(&repo, &revs, "master", 1, None, &settings(&repo)).unwrap();
+        remove_patches(&repo, "master", 1).unwrap();
+        let files = fs::read_dir(patch_dir(&repo, "master", 1));
         assert!(files.is_err());
+        assert!(!repo.path().join("submit").exists());
+
+        // Another version in progress keeps the branch directory around.
+        format_patches(&repo, &revs, "master", 1, None, &settings(&repo)).unwrap();
+        format_patches(&repo, &revs, "master", 2, None, &settings(&repo)).unwrap();
+        remove_patches(&repo, "master", 1).unwrap();
+        assert_eq!(patch_files(&repo, "master", 2).unwrap().len(), 2);
 
         fs::remove_dir_all(repo_path).unwrap();
     }
@@ -508,11 +1574,11 @@ mod tests {
         init_test_repo(repo_path).unwrap();
         let repo = Repository::open(repo_path).unwrap();
 
-        tag_version(&repo, "master", 1).unwrap();
+        tag_version(&repo, "master", 1, &VersionInfo::parse("")).unwrap();
         let tag = repo.find_reference("refs/tags/master-v1").unwrap();
         assert!(tag.is_tag());
         assert_eq!(find_version(&repo, "master").unwrap(), 2);
-        remove_tag(&repo, "master", 1);
+        remove_tag(&repo, "master", 1).unwrap();
         let tag_result = repo.find_reference("refs/tags/master-v1");
         assert!(tag_result.is_err());
 
@@ -525,57 +1591,191 @@ mod tests {
         let repo_path = tempdir.path().to_str().unwrap();
         init_test_repo(repo_path).unwrap();
         let repo = Repository::open(repo_path).unwrap();
-        set_path(&repo);
+        set_path(&repo).unwrap();
 
-        let revs = revs_to_send(&repo).unwrap();
-        format_patches(&revs, "master", 1);
+        let revs = revs_to_send(&repo, &repo.head().unwrap(), None).unwrap();
+        format_patches(&repo, &revs, "master", 1, None, &settings(&repo)).unwrap();
+        let _editor = lock_editor();
         env::set_var("EDITOR", "truncate --size=0");
-        edit_patches(&repo, "master").unwrap();
-        let patch_files = fs::read_dir(format!("{}/output-master", repo_path)).unwrap();
-        for file in patch_files {
+        edit_patches(&repo, "master", 1, &settings(&repo)).unwrap();
+        let files = fs::read_dir(patch_dir(&repo, "master", 1)).unwrap();
+        for file in files {
             assert_eq!(file.unwrap().metadata().unwrap().len(), 0);
         }
 
+        let mut config = repo.config().unwrap();
+        config.set_str("submit.editMode", "all").unwrap();
+        format_patches(&repo, &revs, "master", 1, None, &settings(&repo)).unwrap();
+        edit_patches(&repo, "master", 1, &settings(&repo)).unwrap();
+        for file in patch_files(&repo, "master", 1).unwrap() {
+            assert_eq!(file.metadata().unwrap().len(), 0);
+        }
+        // An empty mbox doesn't have the patches any more.
+        config.set_str("submit.editMode", "mbox").unwrap();
+        format_patches(&repo, &revs, "master", 1, None, &settings(&repo)).unwrap();
+        assert!(edit_patches(&repo, "master", 1, &settings(&repo)).is_err());
+        for file in patch_files(&repo, "master", 1).unwrap() {
+            assert!(file.metadata().unwrap().len() > 0);
+        }
+        assert!(!repo.path().join("SUBMIT_SERIES.mbox").exists());
+        config.set_str("submit.editMode", "pigeon").unwrap();
+        assert!(edit_patches(&repo, "master", 1, &settings(&repo)).is_err());
+
+        fs::remove_dir_all(repo_path).unwrap();
+    }
+
+    #[test]
+    fn test_review_recipients() {
+        let tempdir = Box::new(TempDir::new("git-submit").unwrap());
+        let repo_path = tempdir.path().to_str().unwrap();
+        init_test_repo(repo_path).unwrap();
+        let repo = Repository::open(repo_path).unwrap();
+
+        // The Cc headers of the patches are reviewed along with To and Cc.
+        let files = vec!(tempdir.path().join("0001-one.patch"),
+                         tempdir.path().join("0002-two.patch"));
+        File::create(&files[0]).unwrap()
+            .write_all(b"Subject: one\nCc: maintainer@example.com,\n\
+                         \tJeff King <peff@peff.net>\n\nbody\n").unwrap();
+        File::create(&files[1]).unwrap()
+            .write_all(b"Subject: two\nCc: list@example.com\n\nbody\n").unwrap();
+        let to = vec!(String::from("list@example.com"));
+        let _editor = lock_editor();
+        env::set_var("EDITOR", "sed -i -e /maintainer/d -e $aPatch-Cc:new@example.com");
+        assert_eq!(review_recipients(&repo, &files, &to, &[]).unwrap(),
+                   (to.clone(), vec!(String::from("new@example.com"))));
+        let mut contents = String::new();
+        File::open(&files[0]).unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "Subject: one\nCc: Jeff King <peff@peff.net>\n\nbody\n");
+        let mut contents = String::new();
+        File::open(&files[1]).unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "Subject: two\nCc: list@example.com\n\nbody\n");
+        env::set_var("EDITOR", "sed -i /./d");
+        assert!(review_recipients(&repo, &files, &to, &[]).is_err());
+        assert!(!repo.path().join("SUBMIT_RECIPIENTS").exists());
+        env::set_var("EDITOR", "git-submit-no-such-editor");
+        assert!(review_recipients(&repo, &files, &to, &[]).is_err());
+        assert!(!repo.path().join("SUBMIT_RECIPIENTS").exists());
+
         fs::remove_dir_all(repo_path).unwrap();
     }
 
     #[test]
-    fn test_find_address_name() {
-        let mb = Mailbox::new_with_name(String::from("Test Name"),
-                                        String::from("test@example.com"));
-        assert_eq!(format_addr(mb), String::from("Test Name <test@example.com>"));
+    fn test_add_trailers() {
+        let tempdir = Box::new(TempDir::new("git-submit").unwrap());
+        let repo_path = tempdir.path().to_str().unwrap();
+        init_test_repo(repo_path).unwrap();
+        let repo = Repository::open(repo_path).unwrap();
+        set_path(&repo).unwrap();
+        let mbox_path = tempdir.path().join(".git").join("list.mbox");
+        // Replies to the cover letter and to the second patch, and the
+        // replies to those, threaded the way mail clients do it.
+        File::create(&mbox_path).unwrap()
+            .write_all(b"From 1234 Mon Sep 17 00:00:00 2001\n\
+                         Message-Id: <r1@example.com>\n\
+                         In-Reply-To: <cover@example.com>\n\
+                         References: <cover@example.com>\n\n\
+                         Acked-by: Junio C Hamano <gitster@pobox.com>\n\n\
+                         From 5678 Mon Sep 17 00:00:00 2001\n\
+                         Message-Id: <r2@example.com>\n\
+                         In-Reply-To: <r1@example.com>\n\
+                         References: <cover@example.com> <r1@example.com>\n\n\
+                         Tested-by: Some One <someone@example.com>\n\n\
+                         From 9012 Mon Sep 17 00:00:00 2001\n\
+                         Message-Id: <r3@example.com>\n\
+                         In-Reply-To: <2@example.com>\n\
+                         References: <cover@example.com>\n\t<2@example.com>\n\n\
+                         Reviewed-by: Jeff King <peff@peff.net>\n\n\
+                         From 3456 Mon Sep 17 00:00:00 2001\n\
+                         Message-Id: <r4@example.com>\n\
+                         In-Reply-To: <r3@example.com>\n\
+                         References: <cover@example.com> <2@example.com>\n\
+                         \t<r3@example.com>\n\n\
+                         Acked-by: Other <other@example.com>\n").unwrap();
+        let archive = Mbox::new(&mbox_path);
+        assert!(add_trailers(&repo, "master", &archive, &settings(&repo)).is_err());
+
+        let mut info = VersionInfo::parse("");
+        info.base = Some(repo.revparse_single("HEAD~2").unwrap().id());
+        info.message_ids = vec!(String::from("cover@example.com"), String::from("1@example.com"),
+                                String::from("2@example.com"));
+        tag_version(&repo, "master", 1, &info).unwrap();
+        // The base recorded in the tag wins over the configured one.
+        repo.config().unwrap().set_str("submit.base", "HEAD~1").unwrap();
+        let original = repo.head().unwrap().target().unwrap();
+        assert_eq!(add_trailers(&repo, "master", &archive, &settings(&repo)).unwrap(), 6);
+
+        let head = repo.find_commit(repo.head().unwrap().target().unwrap()).unwrap();
+        assert_eq!(head.tree_id(), repo.find_commit(original).unwrap().tree_id());
+        assert_eq!(head.message(), Some("commit 3\n\n\
+                                         Acked-by: Junio C Hamano <gitster@pobox.com>\n\
+                                         Tested-by: Some One <someone@example.com>\n\
+                                         Reviewed-by: Jeff King <peff@peff.net>\n\
+                                         Acked-by: Other <other@example.com>\n"));
+        assert_eq!(head.parent(0).unwrap().message(),
+                   Some("commit 2\n\nAcked-by: Junio C Hamano <gitster@pobox.com>\n\
+                         Tested-by: Some One <someone@example.com>\n"));
+        assert!(!patch_dir(&repo, "master", 2).exists());
+        assert_eq!(add_trailers(&repo, "master", &archive, &settings(&repo)).unwrap(), 0);
+
+        fs::remove_dir_all(repo_path).unwrap();
     }
 
     #[test]
-    fn test_find_address_no_name() {
-        let mb = Mailbox::new(String::from("test@example.com"));
-        assert_eq!(format_addr(mb), String::from("test@example.com"));
+    fn test_rebuild_branch() {
+        let tempdir = Box::new(TempDir::new("git-submit").unwrap());
+        let repo_path = tempdir.path().to_str().unwrap();
+        init_test_repo(repo_path).unwrap();
+        let repo = Repository::open(repo_path).unwrap();
+        set_path(&repo).unwrap();
+        let original = repo.find_commit(repo.head().unwrap().target().unwrap()).unwrap();
+
+        let revs = revs_to_send(&repo, &repo.head().unwrap(), None).unwrap();
+        format_patches(&repo, &revs, "master", 1, None, &settings(&repo)).unwrap();
+        let files = patch_files(&repo, "master", 1).unwrap();
+        let mut contents = String::new();
+        File::open(&files[0]).unwrap().read_to_string(&mut contents).unwrap();
+        File::create(&files[0]).unwrap()
+            .write_all(contents.replace("] commit 2", "] commit two").as_bytes()).unwrap();
+        rebuild_branch(&repo, &revs, "master", 1).unwrap();
+
+        let head = repo.find_commit(repo.head().unwrap().target().unwrap()).unwrap();
+        assert_eq!(head.message(), Some("commit 3\n"));
+        assert_eq!(head.tree_id(), original.tree_id());
+        assert_eq!(head.author().when().seconds(), original.author().when().seconds());
+        assert_eq!(head.parent(0).unwrap().message(), Some("commit two\n"));
+
+        // Only the cover letter is skipped, even if the branch looks like one.
+        format_patches(&repo, &revs, "topic-0000", 1, None, &settings(&repo)).unwrap();
+        rebuild_branch(&repo, &revs, "topic-0000", 1).unwrap();
+        let head = repo.find_commit(repo.head().unwrap().target().unwrap()).unwrap();
+        assert_eq!(head.message(), Some("commit 3\n"));
+        assert_eq!(head.parent(0).unwrap().message(), Some("commit 2\n"));
+
+        fs::remove_dir_all(repo_path).unwrap();
     }
 
     #[test]
     fn test_find_addresses_command_line() {
         assert_eq!(find_addresses(vec!(String::from("test@example.com"),
-                                       String::from("snd@example.com")), None,
-                                  String::from("To")).unwrap(),
+                                       String::from("snd@example.com")), None, "To"),
                    vec!(String::from("test@example.com"),
                         String::from("snd@example.com")));
     }
 
     #[test]
     fn test_find_to_mail() {
-        assert_eq!(find_addresses(Vec::new(),
-                                  Some(String::from(
-                                      "1453136238-19448-1-git-send-email-t.gummerer@gmail.com")),
-                                  String::from("To")).unwrap(),
+        let archive = PublicInbox::new(serve_message(MESSAGE).as_str());
+        let context = ThreadContext::fetch(&archive, MESSAGE_ID).unwrap();
+        assert_eq!(find_addresses(Vec::new(), Some(&context), "To"),
                    vec!(String::from("git@vger.kernel.org")));
     }
 
     #[test]
     fn test_find_cc_mail() {
-        assert_eq!(find_addresses(Vec::new(),
-                                  Some(String::from(
-                                      "1453136238-19448-1-git-send-email-t.gummerer@gmail.com")),
-                                  String::from("Cc")).unwrap(),
+        let archive = PublicInbox::new(serve_message(MESSAGE).as_str());
+        let context = ThreadContext::fetch(&archive, MESSAGE_ID).unwrap();
+        assert_eq!(find_addresses(Vec::new(), Some(&context), "Cc"),
                    vec!(String::from("peff@peff.net"),
                         String::from("bturner@atlassian.com"),
                         String::from("gitster@pobox.com"),
@@ -583,28 +1783,37 @@ mod tests {
                         String::from("Thomas Gummerer <t.gummerer@gmail.com>")));
     }
 
+    #[test]
+    fn test_find_from_mail_mbox() {
+        let tempdir = TempDir::new("git-submit").unwrap();
+        let path = tempdir.path().join("list.mbox");
+        let mut file = File::create(&path).unwrap();
+        write!(file, "From 1234 Mon Sep 17 00:00:00 2001\n{}", MESSAGE).unwrap();
+
+        let archive = Mbox::new(&path);
+        let context = ThreadContext::fetch(&archive, MESSAGE_ID).unwrap();
+        assert_eq!(find_addresses(Vec::new(), Some(&context), "From"),
+                   vec!(String::from("Thomas Gummerer <t.gummerer@gmail.com>")));
+    }
+
     #[test]
     fn test_find_combined_command_line_to_mail() {
-        assert_eq!(find_addresses(vec!(String::from("test@example.com")),
-                                  Some(String::from(
-                                      "1453136238-19448-1-git-send-email-t.gummerer@gmail.com")),
-                                  String::from("To")).unwrap(),
+        let archive = PublicInbox::new(serve_message(MESSAGE).as_str());
+        let context = ThreadContext::fetch(&archive, MESSAGE_ID).unwrap();
+        assert_eq!(find_addresses(vec!(String::from("test@example.com")), Some(&context), "To"),
                    vec!(String::from("test@example.com"),
                         String::from("git@vger.kernel.org")));
     }
 
     #[test]
     fn test_add_from_addresses() {
-        let to_only = find_addresses(vec!(String::from("test@example.com")),
-                                     Some(String::from(
-                                         "1453136238-19448-1-git-send-email-t.gummerer@gmail.com")),
-                                     String::from("To")).unwrap();
+        let archive = Gmane::new(serve_message(MESSAGE).as_str());
+        let context = ThreadContext::fetch(&archive, MESSAGE_ID).unwrap();
+        let to_only = find_addresses(vec!(String::from("test@example.com")), Some(&context),
+                                     "To");
         assert_eq!(to_only, vec!(String::from("test@example.com"),
                                  String::from("git@vger.kernel.org")));
-        assert_eq!(find_addresses(to_only,
-                                  Some(String::from(
-                                      "1453136238-19448-1-git-send-email-t.gummerer@gmail.com")),
-                                  String::from("From")).unwrap(),
+        assert_eq!(find_addresses(to_only, Some(&context), "From"),
                    vec!(String::from("test@example.com"),
                         String::from("git@vger.kernel.org"),
                         String::from("Thomas Gummerer <t.gummerer@gmail.com>")));
@@ -0,0 +1,94 @@
+use address;
+use error::SubmitError;
+
+const INSTRUCTIONS: &'static str = "\
+# The series is about to be sent to the addresses below.  Remove the
+# ones it shouldn't go to, move them between To and Cc, or add new ones,
+# one per line.  Patch-Cc addresses only get the patches that asked for
+# them, through their trailers or submit.ccCmd, and the cover letter.
+# Lines starting with '#' are ignored.  Removing all To and Cc addresses
+# stops the submission.
+";
+
+/// The To and Cc lists, the way they are shown to the user for review
+/// before the series is sent, followed by the addresses in the Cc
+/// headers of the patch files.
+pub fn format(to: &[String], cc: &[String], patch_cc: &[String]) -> String {
+    let mut contents = String::from(INSTRUCTIONS);
+    for addr in to {
+        contents.push_str(format!("To: {}\n", addr).as_str());
+    }
+    for addr in cc {
+        contents.push_str(format!("Cc: {}\n", addr).as_str());
+    }
+    for addr in patch_cc {
+        contents.push_str(format!("Patch-Cc: {}\n", addr).as_str());
+    }
+    contents
+}
+
+/// Read the To, Cc and Patch-Cc lists back after the user reviewed them.
+/// Every address has to be given as `To: <address>`, `Cc: <address>` or
+/// `Patch-Cc: <address>`.  An address that is also in To or Cc is dropped
+/// from Patch-Cc.
+pub fn parse(contents: &str)
+             -> Result<(Vec<String>, Vec<String>, Vec<String>), SubmitError> {
+    let mut to = Vec::new();
+    let mut cc = Vec::new();
+    let mut patch_cc = Vec::new();
+    for line in contents.lines().map(|l| l.trim()) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let lower = line.to_lowercase();
+        if lower.starts_with("to:") {
+            to.push(line[3..].trim().to_owned());
+        } else if lower.starts_with("cc:") {
+            cc.push(line[3..].trim().to_owned());
+        } else if lower.starts_with("patch-cc:") {
+            patch_cc.push(line[9..].trim().to_owned());
+        } else {
+            return Err(SubmitError::Usage(format!(
+                "'{}' is neither a To:, a Cc: nor a Patch-Cc: line", line)));
+        }
+    }
+    let (to, cc) = address::dedup(to, cc, &[]);
+    let (patch_cc, _) = address::dedup(patch_cc, Vec::new(), &to.iter()
+                                       .chain(cc.iter())
+                                       .cloned()
+                                       .collect::<Vec<_>>());
+    Ok((to, cc, patch_cc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format, parse};
+
+    #[test]
+    fn test_format_recipients() {
+        let contents = format(&[String::from("list@example.com")],
+                              &[String::from("Jeff King <peff@peff.net>")],
+                              &[String::from("maintainer@example.com")]);
+        assert!(contents.starts_with("# "));
+        assert!(contents.ends_with("\nTo: list@example.com\nCc: Jeff King <peff@peff.net>\n\
+                                    Patch-Cc: maintainer@example.com\n"));
+        assert_eq!(parse(contents.as_str()).unwrap(),
+                   (vec!(String::from("list@example.com")),
+                    vec!(String::from("Jeff King <peff@peff.net>")),
+                    vec!(String::from("maintainer@example.com"))));
+    }
+
+    #[test]
+    fn test_parse_recipients() {
+        let contents = "# comment\n\ncc: list@example.com\nTo: Jeff King <peff@peff.net>\n\
+                        To:   new@example.com  \nCC: peff@peff.net\n\
+                        patch-cc: PEFF@peff.net\nPatch-Cc: maintainer@example.com\n";
+        assert_eq!(parse(contents).unwrap(),
+                   (vec!(String::from("Jeff King <peff@peff.net>"),
+                         String::from("new@example.com")),
+                    vec!(String::from("list@example.com")),
+                    vec!(String::from("maintainer@example.com"))));
+        assert_eq!(parse("# nothing left\n").unwrap(), (Vec::new(), Vec::new(), Vec::new()));
+        assert!(parse("list@example.com\n").is_err());
+    }
+}
@@ -0,0 +1,150 @@
+use error::SubmitError;
+use git2::{Oid, Repository};
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::PathBuf;
+
+/// Everything that is needed to tag and send a series once the branch
+/// has been re-built.  It is saved when a patch did not apply, so `git
+/// submit --continue` and `git submit --abort` can pick it up.
+#[derive(Debug, PartialEq)]
+pub struct State {
+    pub branch: String,
+    pub head: Oid,
+    pub version: u32,
+    pub base: Option<Oid>,
+    pub failed: String,
+    pub in_reply_to: Option<String>,
+    /// The messages of the thread `in_reply_to` is in, oldest first.
+    pub references: Vec<String>,
+    pub to: Vec<String>,
+    pub cc: Vec<String>,
+    pub dry_run: bool,
+}
+
+impl State {
+    pub fn save(&self, repo: &Repository) -> Result<(), SubmitError> {
+        let mut file = try!(File::create(state_path(repo)));
+        try!(writeln!(file, "branch {}", self.branch));
+        try!(writeln!(file, "head {}", self.head));
+        try!(writeln!(file, "version {}", self.version));
+        if let Some(base) = self.base {
+            try!(writeln!(file, "base {}", base));
+        }
+        try!(writeln!(file, "failed {}", self.failed));
+        if let Some(ref in_reply_to) = self.in_reply_to {
+            try!(writeln!(file, "in-reply-to {}", in_reply_to));
+        }
+        for reference in self.references.iter() {
+            try!(writeln!(file, "references {}", reference));
+        }
+        for addr in self.to.iter() {
+            try!(writeln!(file, "to {}", addr));
+        }
+        for addr in self.cc.iter() {
+            try!(writeln!(file, "cc {}", addr));
+        }
+        if self.dry_run {
+            try!(writeln!(file, "dry-run"));
+        }
+        Ok(())
+    }
+
+    /// Load the saved state, if a submission is in progress.
+    pub fn load(repo: &Repository) -> Result<Option<State>, SubmitError> {
+        let path = state_path(repo);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let mut contents = String::new();
+        try!(try!(File::open(path)).read_to_string(&mut contents));
+
+        let mut branch = None;
+        let mut head = None;
+        let mut version = None;
+        let mut base = None;
+        let mut failed = None;
+        let mut in_reply_to = None;
+        let mut references = Vec::new();
+        let mut to = Vec::new();
+        let mut cc = Vec::new();
+        let mut dry_run = false;
+        for line in contents.lines() {
+            let mut split = line.splitn(2, ' ');
+            let key = split.next().unwrap_or("");
+            let value = split.next().unwrap_or("").to_owned();
+            match key {
+                "branch" => branch = Some(value),
+                "head" => head = Some(try!(Oid::from_str(value.as_str()))),
+                "version" => version = value.parse::<u32>().ok(),
+                "base" => base = Some(try!(Oid::from_str(value.as_str()))),
+                "failed" => failed = Some(value),
+                "in-reply-to" => in_reply_to = Some(value),
+                "references" => references.push(value),
+                "to" => to.push(value),
+                "cc" => cc.push(value),
+                "dry-run" => dry_run = true,
+                _ => (),
+            }
+        }
+        match (branch, head, version, failed) {
+            (Some(branch), Some(head), Some(version), Some(failed)) => Ok(Some(State {
+                branch: branch,
+                head: head,
+                version: version,
+                base: base,
+                failed: failed,
+                in_reply_to: in_reply_to,
+                references: references,
+                to: to,
+                cc: cc,
+                dry_run: dry_run,
+            })),
+            _ => Err(SubmitError::Usage(format!("{} is corrupt, remove it to start over",
+                                                state_path(repo).display()))),
+        }
+    }
+
+    pub fn remove(repo: &Repository) -> Result<(), SubmitError> {
+        try!(fs::remove_file(state_path(repo)));
+        Ok(())
+    }
+}
+
+fn state_path(repo: &Repository) -> PathBuf {
+    repo.path().join("submit-state")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::State;
+
+    use git2::{Oid, Repository};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_state_roundtrip() {
+        let tempdir = TempDir::new("git-submit").unwrap();
+        let repo = Repository::init(tempdir.path()).unwrap();
+        assert_eq!(State::load(&repo).unwrap(), None);
+
+        let state = State {
+            branch: String::from("topic/feature"),
+            head: Oid::from_str("0123456789abcdef0123456789abcdef01234567").unwrap(),
+            version: 2,
+            base: Some(Oid::from_str("89abcdef0123456789abcdef0123456789abcdef").unwrap()),
+            failed: String::from("/tmp/repo/.git/submit/topic/feature/v2/v2-0002-second.patch"),
+            in_reply_to: Some(String::from("cover@example.com")),
+            references: vec!(String::from("root@example.com"),
+                             String::from("reply@example.com")),
+            to: vec!(String::from("A U Thor <author@example.net>")),
+            cc: vec!(String::from("list@example.com"), String::from("other@example.com")),
+            dry_run: true,
+        };
+        state.save(&repo).unwrap();
+        assert_eq!(State::load(&repo).unwrap(), Some(state));
+
+        State::remove(&repo).unwrap();
+        assert_eq!(State::load(&repo).unwrap(), None);
+    }
+}
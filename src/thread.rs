@@ -0,0 +1,95 @@
+use address;
+use archive::{self, Archive};
+use email::{HeaderMap, MimeMessage};
+use error::SubmitError;
+
+/// The message a patch series is sent in reply to.  It is fetched from
+/// the archive once, and everything that needs to know about it is
+/// derived from this.
+pub struct ThreadContext {
+    pub message_id: String,
+    pub references: Vec<String>,
+    pub from: Vec<String>,
+    pub to: Vec<String>,
+    pub cc: Vec<String>,
+}
+
+impl ThreadContext {
+    pub fn fetch(archive: &Archive, message_id: &str) -> Result<ThreadContext, SubmitError> {
+        let body = try!(archive.fetch(message_id));
+        ThreadContext::parse(message_id, body.as_str())
+    }
+
+    pub fn parse(message_id: &str, raw: &str) -> Result<ThreadContext, SubmitError> {
+        let headers = try!(MimeMessage::parse(raw)).headers;
+        let references = match headers.get_value::<String>(String::from("References")) {
+            Ok(refs) => refs.split_whitespace()
+                .map(|r| r.trim_matches(|c: char| c == '<' || c == '>').to_owned())
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        Ok(ThreadContext {
+            message_id: archive::message_id(raw).unwrap_or(
+                message_id.trim_matches(|c: char| c == '<' || c == '>').to_owned()),
+            references: references,
+            from: try!(addresses(&headers, "From")),
+            to: try!(addresses(&headers, "To")),
+            cc: try!(addresses(&headers, "Cc")),
+        })
+    }
+}
+
+/// The addresses in a header, none if there is no such header, and an
+/// error if it is there but can't be parsed.
+fn addresses(headers: &HeaderMap, addr_type: &str) -> Result<Vec<String>, SubmitError> {
+    match headers.get(String::from(addr_type)) {
+        Some(header) => address::parse_header(header),
+        None => Ok(Vec::new()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ThreadContext;
+
+    use archive::PublicInbox;
+    use error::SubmitError;
+    use archive::tests::{MESSAGE, MESSAGE_ID, serve_message};
+
+    #[test]
+    fn test_thread_context_fetch() {
+        let archive = PublicInbox::new(serve_message(MESSAGE).as_str());
+        let context = ThreadContext::fetch(&archive, MESSAGE_ID).unwrap();
+        assert_eq!(context.message_id, MESSAGE_ID);
+        assert!(context.references.is_empty());
+        assert_eq!(context.from, vec!(String::from("Thomas Gummerer <t.gummerer@gmail.com>")));
+        assert_eq!(context.to, vec!(String::from("git@vger.kernel.org")));
+        assert_eq!(context.cc.len(), 5);
+    }
+
+    #[test]
+    fn test_thread_context_references() {
+        let context = ThreadContext::parse("<reply@example.com>",
+                                           "From: a@example.com\n\
+                                            Cc: \"King, Jeff\" <peff@peff.net>\n\
+                                            References: <one@example.com>\n \
+                                            <two@example.com>\n\n").unwrap();
+        assert_eq!(context.message_id, "reply@example.com");
+        assert_eq!(context.references,
+                   vec!(String::from("one@example.com"), String::from("two@example.com")));
+        assert_eq!(context.from, vec!(String::from("a@example.com")));
+        assert!(context.to.is_empty());
+        assert_eq!(context.cc, vec!(String::from("\"King, Jeff\" <peff@peff.net>")));
+    }
+
+    #[test]
+    fn test_thread_context_bad_address() {
+        match ThreadContext::parse("reply@example.com", "From: a@example.com
+To: @@@
+
+") {
+            Err(SubmitError::Parse(_)) => (),
+            _ => panic!("the To header should not parse"),
+        }
+    }
+}
@@ -0,0 +1,233 @@
+use archive::{self, Archive};
+use error::SubmitError;
+use regex::Regex;
+
+/// The trailers reviewers give in their replies, which are carried over
+/// to the commits.
+const KINDS: [&'static str; 3] = ["Reviewed-by", "Acked-by", "Tested-by"];
+
+/// The `Reviewed-by`, `Acked-by` and `Tested-by` trailers in the body of
+/// a reply.  Quoted lines are skipped, they are someone else's.
+pub fn from_reply(message: &str) -> Vec<String> {
+    let re = Regex::new(r"^(?i)(reviewed|acked|tested)-by:\s*(.+)$").unwrap();
+    let mut trailers = Vec::new();
+    for line in message.lines().skip_while(|l| !l.is_empty()) {
+        if let Some(caps) = re.captures(line.trim_right()) {
+            let kind = caps.at(1).unwrap_or("").to_lowercase();
+            let kind = KINDS.iter().find(|k| k.to_lowercase().starts_with(kind.as_str())).unwrap();
+            let trailer = format!("{}: {}", kind, caps.at(2).unwrap_or("").trim());
+            if !trailers.contains(&trailer) {
+                trailers.push(trailer);
+            }
+        }
+    }
+    trailers
+}
+
+/// All the trailers given in the replies to a message, and in the
+/// replies to those.  Replies that are in the thread below one of the
+/// `excluded` messages are left out, the patches of a series are replies
+/// to its cover letter, but what is given for them isn't for the cover
+/// letter.
+pub fn collect(archive: &Archive, message_id: &str, excluded: &[String])
+               -> Result<Vec<String>, SubmitError> {
+    let mut trailers = Vec::new();
+    for reply in try!(archive.replies(message_id)) {
+        if archive::references(reply.as_str()).iter().any(|id| excluded.contains(id)) {
+            continue;
+        }
+        for trailer in from_reply(reply.as_str()) {
+            if !trailers.contains(&trailer) {
+                trailers.push(trailer);
+            }
+        }
+    }
+    Ok(trailers)
+}
+
+/// Add trailers to a commit message that doesn't have them yet.  They go
+/// into the trailer block at the end of the message if there is one, or
+/// start a new one.
+pub fn add(message: &str, trailers: &[String]) -> String {
+    let message = message.trim_right();
+    let new = trailers.iter()
+        .filter(|t| !message.lines().any(|l| l.trim() == t.as_str()))
+        .map(|t| t.as_str())
+        .collect::<Vec<_>>();
+    if new.is_empty() {
+        return format!("{}\n", message);
+    }
+    let re = Regex::new(r"^[A-Za-z0-9-]+: ").unwrap();
+    let last_paragraph = message.rsplit("\n\n").next().unwrap_or("");
+    if message.is_empty() {
+        format!("{}\n", new.join("\n"))
+    } else if message.contains("\n\n") && last_paragraph.lines().all(|l| re.is_match(l)) {
+        format!("{}\n{}\n", message, new.join("\n"))
+    } else {
+        format!("{}\n\n{}\n", message, new.join("\n"))
+    }
+}
+
+/// Where the commit message in a patch mail starts and ends.  It sits
+/// between the headers and the `---` line.
+fn message_bounds(patch: &str) -> Option<(usize, usize)> {
+    let start = match patch.find("\n\n") {
+        Some(end) => end + 2,
+        None => return None,
+    };
+    if patch[start..].starts_with("---\n") {
+        return Some((start, start));
+    }
+    patch[start..].find("\n---\n").map(|end| (start, start + end + 1))
+}
+
+/// Add trailers to the commit message in a patch mail.  Only the part
+/// up to the `---` line is touched, the diff is kept byte for byte.
+pub fn add_to_patch(patch: &[u8], trailers: &[String]) -> Vec<u8> {
+    let split = match patch.windows(5).position(|w| w == b"\n---\n") {
+        Some(pos) => pos + 5,
+        None => patch.len(),
+    };
+    let head = String::from_utf8_lossy(&patch[..split]);
+    let mut result = match message_bounds(&head) {
+        Some((start, end)) =>
+            format!("{}{}{}", &head[..start], add(&head[start..end], trailers), &head[end..])
+                .into_bytes(),
+        None => return patch.to_vec(),
+    };
+    result.extend_from_slice(&patch[split..]);
+    result
+}
+
+/// The addresses in the `Signed-off-by`, `Cc` and other `-by` trailers
+/// of a commit message, which the patch is cc'd to.  Comments after a
+/// `#`, as in `Cc: <stable@vger.kernel.org> # 4.4+`, are dropped.
+pub fn addresses(message: &str) -> Vec<String> {
+    let re = Regex::new(r"^(?i)(?:([a-z]+-)+by|cc):\s*(.+@.+)$").unwrap();
+    let mut addresses = Vec::new();
+    for line in message.lines() {
+        if let Some(caps) = re.captures(line.trim_right()) {
+            let value = caps.at(2).unwrap_or("");
+            let addr = String::from(value.split('#').next().unwrap_or("").trim());
+            if !addr.is_empty() && !addresses.contains(&addr) {
+                addresses.push(addr);
+            }
+        }
+    }
+    addresses
+}
+
+/// The addresses in the trailers of the commit message in a patch mail.
+pub fn patch_addresses(patch: &str) -> Vec<String> {
+    match message_bounds(patch) {
+        Some((start, end)) => addresses(&patch[start..end]),
+        None => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{add, add_to_patch, addresses, collect, from_reply, patch_addresses};
+
+    use archive::Mbox;
+    use std::fs::File;
+    use std::io::Write;
+    use tempdir::TempDir;
+
+    const REPLY: &'static str = "\
+From: Jeff King <peff@peff.net>
+Subject: Re: [PATCH 1/2] commit 2
+In-Reply-To: <1@example.com>
+References: <cover@example.com> <1@example.com>
+Message-Id: <reply@example.com>
+
+On Mon, Jan 18, 2016 at 05:57:18PM +0100, A U Thor wrote:
+> Signed-off-by: A U Thor <author@example.net>
+> Reviewed-by: Someone Else <else@example.com>
+
+Looks good.
+
+reviewed-by: Jeff King <peff@peff.net>
+Tested-by: Jeff King <peff@peff.net>
+";
+
+    #[test]
+    fn test_from_reply() {
+        assert_eq!(from_reply(REPLY),
+                   vec!(String::from("Reviewed-by: Jeff King <peff@peff.net>"),
+                        String::from("Tested-by: Jeff King <peff@peff.net>")));
+        assert_eq!(from_reply("Subject: Acked-by: nobody\n\nNo.\n"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_collect() {
+        let tempdir = TempDir::new("git-submit").unwrap();
+        let path = tempdir.path().join("list.mbox");
+        let mut file = File::create(&path).unwrap();
+        write!(file, "From 1234 Mon Sep 17 00:00:00 2001\n\
+                      Message-Id: <1@example.com>\n\nThe patch\n\n\
+                      From 5678 Mon Sep 17 00:00:00 2001\n{}\n\
+                      From 9012 Mon Sep 17 00:00:00 2001\n\
+                      In-Reply-To: <reply@example.com>\n\
+                      References: <1@example.com>\n <reply@example.com>\n\n\
+                      Acked-by: Junio C Hamano <gitster@pobox.com>\n", REPLY).unwrap();
+
+        let trailers = collect(&Mbox::new(&path), "1@example.com", &[]).unwrap();
+        assert_eq!(trailers, vec!(String::from("Reviewed-by: Jeff King <peff@peff.net>"),
+                                  String::from("Tested-by: Jeff King <peff@peff.net>"),
+                                  String::from("Acked-by: Junio C Hamano <gitster@pobox.com>")));
+        assert_eq!(collect(&Mbox::new(&path), "2@example.com", &[]).unwrap(),
+                   Vec::<String>::new());
+        // The review of the patch is not for the cover letter it replies to.
+        assert_eq!(collect(&Mbox::new(&path), "cover@example.com",
+                           &[String::from("1@example.com")]).unwrap(),
+                   Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_add() {
+        let trailers = vec!(String::from("Reviewed-by: Jeff King <peff@peff.net>"));
+        assert_eq!(add("commit 2", &trailers),
+                   "commit 2\n\nReviewed-by: Jeff King <peff@peff.net>\n");
+        assert_eq!(add("commit 2\n\nSigned-off-by: A U Thor <author@example.net>\n", &trailers),
+                   "commit 2\n\nSigned-off-by: A U Thor <author@example.net>\n\
+                    Reviewed-by: Jeff King <peff@peff.net>\n");
+        assert_eq!(add("commit 2\n\nReviewed-by: Jeff King <peff@peff.net>\n", &trailers),
+                   "commit 2\n\nReviewed-by: Jeff King <peff@peff.net>\n");
+        assert_eq!(add("", &trailers), "Reviewed-by: Jeff King <peff@peff.net>\n");
+    }
+
+    #[test]
+    fn test_add_to_patch() {
+        let trailers = vec!(String::from("Acked-by: Junio C Hamano <gitster@pobox.com>"));
+        let patch = b"From: A U Thor <author@example.net>\nSubject: [PATCH] commit 2\n\n\
+                      ---\n 2 | 1 +\n";
+        assert_eq!(add_to_patch(patch, &trailers),
+                   b"From: A U Thor <author@example.net>\nSubject: [PATCH] commit 2\n\n\
+                     Acked-by: Junio C Hamano <gitster@pobox.com>\n---\n 2 | 1 +\n".to_vec());
+        let patch = b"Subject: [PATCH] commit 2\n\nThe body.\n---\n 2 | 1 +\n+caf\xe9\n";
+        assert_eq!(add_to_patch(patch, &trailers),
+                   b"Subject: [PATCH] commit 2\n\nThe body.\n\n\
+                     Acked-by: Junio C Hamano <gitster@pobox.com>\n---\n 2 | 1 +\n+caf\xe9\n"
+                   .to_vec());
+    }
+
+    #[test]
+    fn test_addresses() {
+        let message = "commit 2\n\nReported-by: Some One <someone@example.com>\n\
+                       Cc: <stable@vger.kernel.org> # 4.4+\n\
+                       Signed-off-by: A U Thor <author@example.net>\n\
+                       reviewed-by: Jeff King <peff@peff.net>\n\
+                       Signed-off-by: A U Thor <author@example.net>\n\
+                       Link: https://example.com/\nFixes-by: nobody\n";
+        assert_eq!(addresses(message),
+                   vec!(String::from("Some One <someone@example.com>"),
+                        String::from("<stable@vger.kernel.org>"),
+                        String::from("A U Thor <author@example.net>"),
+                        String::from("Jeff King <peff@peff.net>")));
+        let patch = "Subject: [PATCH] commit 2\n\nSigned-off-by: A U Thor <author@example.net>\n\
+                     ---\n Cc: not@example.com\n";
+        assert_eq!(patch_addresses(patch), vec!(String::from("A U Thor <author@example.net>")));
+        assert_eq!(patch_addresses("Subject: no message\n"), Vec::<String>::new());
+    }
+}
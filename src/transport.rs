@@ -0,0 +1,1128 @@
+use address;
+use archive;
+use email::Mailbox;
+use error::SubmitError;
+use format;
+use git2::Config;
+use openssl::ssl::{SSL_VERIFY_PEER, SslContext, SslMethod, SslStream};
+use openssl::ssl::error::SslError;
+use openssl::x509::X509;
+use rustc_serialize::base64::{STANDARD, ToBase64};
+use std::env;
+use std::ffi::CString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, TcpStream};
+use std::os::raw::{c_char, c_int, c_uint, c_void};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::ptr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use trailers;
+
+/// Where the system's CA certificates usually are, for when
+/// `sendemail.smtpSslCertPath` isn't set.
+const CA_FILES: [&'static str; 5] = ["/etc/ssl/certs/ca-certificates.crt",
+                                     "/etc/pki/tls/certs/ca-bundle.crt",
+                                     "/etc/ssl/ca-bundle.pem",
+                                     "/etc/ssl/cert.pem",
+                                     "/usr/local/etc/openssl/cert.pem"];
+
+// libcrypto's checks of a certificate against a host name or address,
+// which the openssl crate doesn't wrap.
+extern "C" {
+    fn X509_check_host(cert: *mut c_void, name: *const c_char, len: usize, flags: c_uint,
+                       peername: *mut *mut c_char) -> c_int;
+    fn X509_check_ip_asc(cert: *mut c_void, address: *const c_char, flags: c_uint) -> c_int;
+}
+
+/// A way of delivering a single, fully formatted message.  The message
+/// is bytes, as the patch in it may not be UTF-8.
+pub trait Transport {
+    fn send(&mut self, from: &str, recipients: &[String], message: &[u8])
+            -> Result<(), SubmitError>;
+}
+
+#[derive(Debug, PartialEq)]
+enum Encryption {
+    None,
+    StartTls,
+    Tls,
+}
+
+/// Speaks SMTP to the server configured in the `sendemail.*` keys, the
+/// same ones `git send-email` uses.
+pub struct Smtp {
+    server: String,
+    port: u16,
+    encryption: Encryption,
+    domain: String,
+    user: Option<String>,
+    pass: Option<String>,
+    ca_file: Option<PathBuf>,
+}
+
+impl Smtp {
+    pub fn from_config(config: &Config) -> Result<Smtp, SubmitError> {
+        let encryption = match config.get_string("sendemail.smtpEncryption") {
+            Ok(ref enc) if enc == "tls" => Encryption::StartTls,
+            Ok(ref enc) if enc == "ssl" => Encryption::Tls,
+            _ => Encryption::None,
+        };
+        let port = match config.get_i32("sendemail.smtpServerPort") {
+            Ok(port) if port > 0 && port <= 65535 => port as u16,
+            Ok(port) => return Err(SubmitError::Usage(format!(
+                "sendemail.smtpServerPort {} is not a valid port", port))),
+            Err(_) => match encryption {
+                Encryption::None => 25,
+                Encryption::StartTls => 587,
+                Encryption::Tls => 465,
+            },
+        };
+        Ok(Smtp {
+            server: config.get_string("sendemail.smtpServer")
+                .unwrap_or(String::from("localhost")),
+            port: port,
+            encryption: encryption,
+            domain: config.get_string("sendemail.smtpDomain")
+                .unwrap_or(String::from("localhost")),
+            user: config.get_string("sendemail.smtpUser").ok(),
+            pass: config.get_string("sendemail.smtpPass").ok(),
+            ca_file: config.get_path("sendemail.smtpSslCertPath").ok(),
+        })
+    }
+
+    /// The server's certificate is always verified, against
+    /// `sendemail.smtpSslCertPath` or else the system's CA certificates,
+    /// as the password is sent over the connection.
+    fn tls_context(&self) -> Result<SslContext, SubmitError> {
+        let mut ctx = try!(SslContext::new(SslMethod::Sslv23).map_err(tls_error));
+        let ca_file = match self.ca_file {
+            Some(ref ca_file) => ca_file.clone(),
+            None => match default_ca_file() {
+                Some(ca_file) => ca_file,
+                None => return Err(SubmitError::SendEmail(String::from(
+                    "no CA certificates to verify the SMTP server with, set \
+                     sendemail.smtpSslCertPath"))),
+            },
+        };
+        try!(ctx.set_CA_file(&ca_file).map_err(tls_error));
+        ctx.set_verify(SSL_VERIFY_PEER, None);
+        Ok(ctx)
+    }
+
+    /// Start TLS on the connection, and make sure the certificate is the
+    /// one of `sendemail.smtpServer`.
+    fn connect_tls(&self, tcp: TcpStream) -> Result<Stream, SubmitError> {
+        let ctx = try!(self.tls_context());
+        let stream = try!(SslStream::connect(&ctx, tcp).map_err(tls_error));
+        let valid = stream.ssl().peer_certificate()
+            .map_or(false, |cert| matches_host(&cert, self.server.as_str()));
+        if !valid {
+            return Err(SubmitError::SendEmail(format!(
+                "the certificate of the SMTP server is not valid for {}", self.server)));
+        }
+        Ok(Stream::Tls(stream))
+    }
+}
+
+fn default_ca_file() -> Option<PathBuf> {
+    env::var_os("SSL_CERT_FILE").map(PathBuf::from).into_iter()
+        .chain(CA_FILES.iter().map(|f| PathBuf::from(*f)))
+        .find(|f| f.is_file())
+}
+
+fn matches_host(cert: &X509, host: &str) -> bool {
+    let name = match CString::new(host) {
+        Ok(name) => name,
+        Err(_) => return false,
+    };
+    let handle = cert.get_handle() as *mut c_void;
+    unsafe {
+        if host.parse::<IpAddr>().is_ok() {
+            X509_check_ip_asc(handle, name.as_ptr(), 0) == 1
+        } else {
+            X509_check_host(handle, name.as_ptr(), host.len(), 0, ptr::null_mut()) == 1
+        }
+    }
+}
+
+impl Transport for Smtp {
+    fn send(&mut self, from: &str, recipients: &[String], message: &[u8])
+            -> Result<(), SubmitError> {
+        let tcp = try!(TcpStream::connect((self.server.as_str(), self.port)));
+        let mut stream = if self.encryption == Encryption::Tls {
+            try!(self.connect_tls(tcp))
+        } else {
+            Stream::Plain(tcp)
+        };
+        try!(expect(&mut stream, 220));
+        try!(command(&mut stream, format!("EHLO {}", self.domain).as_str(), 250));
+        if self.encryption == Encryption::StartTls {
+            try!(command(&mut stream, "STARTTLS", 220));
+            stream = match stream {
+                Stream::Plain(tcp) => try!(self.connect_tls(tcp)),
+                tls => tls,
+            };
+            try!(command(&mut stream, format!("EHLO {}", self.domain).as_str(), 250));
+        }
+        if let Some(ref user) = self.user {
+            let pass = self.pass.as_ref().map_or("", |p| p.as_str());
+            let token = format!("\0{}\0{}", user, pass).as_bytes().to_base64(STANDARD);
+            try!(command(&mut stream, format!("AUTH PLAIN {}", token).as_str(), 235));
+        }
+        try!(command(&mut stream, format!("MAIL FROM:<{}>", bare_address(from)).as_str(), 250));
+        for rcpt in recipients {
+            try!(command(&mut stream, format!("RCPT TO:<{}>", bare_address(rcpt)).as_str(), 250));
+        }
+        try!(command(&mut stream, "DATA", 354));
+        for line in lines(message) {
+            if line.starts_with(b".") {
+                try!(stream.write_all(b"."));
+            }
+            try!(stream.write_all(line));
+            try!(stream.write_all(b"\r\n"));
+        }
+        try!(command(&mut stream, ".", 250));
+        command(&mut stream, "QUIT", 221)
+    }
+}
+
+/// Pipes every message to a sendmail compatible command, for setups
+/// where a local MTA takes care of delivery.
+pub struct Sendmail {
+    command: String,
+}
+
+impl Sendmail {
+    pub fn new(command: &str) -> Sendmail {
+        Sendmail { command: command.to_owned() }
+    }
+}
+
+impl Transport for Sendmail {
+    fn send(&mut self, from: &str, recipients: &[String], message: &[u8])
+            -> Result<(), SubmitError> {
+        // Run through the shell like send-email does, so the command may
+        // contain arguments of its own.
+        let mut command = Command::new("sh");
+        command.arg("-c");
+        command.arg(format!("{} \"$@\"", self.command));
+        command.arg("sendmail");
+        command.arg("-i");
+        command.arg(format!("-f{}", bare_address(from)));
+        for rcpt in recipients {
+            command.arg(bare_address(rcpt));
+        }
+        command.stdin(Stdio::piped());
+        let mut child = try!(command.spawn());
+        if let Some(ref mut stdin) = child.stdin {
+            try!(stdin.write_all(message));
+        }
+        // Close stdin, so the command sees the end of the message.
+        drop(child.stdin.take());
+        let status = try!(child.wait());
+        if !status.success() {
+            return Err(SubmitError::SendEmail(format!("{} failed", self.command)));
+        }
+        Ok(())
+    }
+}
+
+/// Appends the messages to an mbox file instead of sending them.
+pub struct Mbox {
+    path: PathBuf,
+}
+
+impl Mbox {
+    pub fn new(path: &Path) -> Mbox {
+        Mbox { path: path.to_path_buf() }
+    }
+}
+
+impl Transport for Mbox {
+    fn send(&mut self, _from: &str, _recipients: &[String], message: &[u8])
+            -> Result<(), SubmitError> {
+        let mut file = try!(OpenOptions::new().append(true).create(true).open(&self.path));
+        let mut contents = b"From git-submit Mon Sep 17 00:00:00 2001\n".to_vec();
+        for line in lines(message) {
+            if line.iter().skip_while(|&&b| b == b'>').take(5).eq(b"From ".iter()) {
+                contents.push(b'>');
+            }
+            contents.extend_from_slice(line);
+            contents.push(b'\n');
+        }
+        contents.push(b'\n');
+        try!(file.write_all(&contents));
+        Ok(())
+    }
+}
+
+/// Delivers the messages into a Maildir instead of sending them.
+pub struct Maildir {
+    path: PathBuf,
+    count: usize,
+}
+
+impl Maildir {
+    pub fn new(path: &Path) -> Maildir {
+        Maildir { path: path.to_path_buf(), count: 0 }
+    }
+}
+
+impl Transport for Maildir {
+    fn send(&mut self, _from: &str, _recipients: &[String], message: &[u8])
+            -> Result<(), SubmitError> {
+        for dir in &["tmp", "new", "cur"] {
+            try!(fs::create_dir_all(self.path.join(dir)));
+        }
+        let now = try!(SystemTime::now().duration_since(UNIX_EPOCH)
+                       .map_err(|e| io::Error::new(io::ErrorKind::Other, e)));
+        self.count += 1;
+        let name = format!("{}.{}_{}.git-submit", now.as_secs(), now.subsec_nanos(), self.count);
+        let tmp = self.path.join("tmp").join(name.as_str());
+        let mut file = try!(File::create(&tmp));
+        try!(file.write_all(message));
+        try!(file.sync_all());
+        try!(fs::rename(&tmp, self.path.join("new").join(name.as_str())));
+        Ok(())
+    }
+}
+
+/// The lines of a message without their line endings, like `str::lines`
+/// does for strings.
+fn lines(message: &[u8]) -> Vec<&[u8]> {
+    let mut lines = message.split(|&b| b == b'\n')
+        .map(|l| if l.ends_with(b"\r") { &l[..l.len() - 1] } else { l })
+        .collect::<Vec<_>>();
+    if message.is_empty() || message.ends_with(b"\n") {
+        lines.pop();
+    }
+    lines
+}
+
+enum Stream {
+    Plain(TcpStream),
+    Tls(SslStream<TcpStream>),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Plain(ref mut s) => s.read(buf),
+            Stream::Tls(ref mut s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Plain(ref mut s) => s.write(buf),
+            Stream::Tls(ref mut s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::Plain(ref mut s) => s.flush(),
+            Stream::Tls(ref mut s) => s.flush(),
+        }
+    }
+}
+
+fn tls_error(e: SslError) -> SubmitError {
+    SubmitError::from(io::Error::new(io::ErrorKind::Other, e))
+}
+
+fn command(stream: &mut Stream, line: &str, code: u32) -> Result<(), SubmitError> {
+    try!(stream.write_all(line.as_bytes()));
+    try!(stream.write_all(b"\r\n"));
+    try!(stream.flush());
+    expect(stream, code)
+}
+
+/// Read a (possibly multi-line) reply and make sure it has the expected
+/// status code.
+fn expect(stream: &mut Stream, code: u32) -> Result<(), SubmitError> {
+    loop {
+        let mut line = Vec::new();
+        let mut byte = [0];
+        while !line.ends_with(b"\r\n") {
+            if try!(stream.read(&mut byte)) == 0 {
+                return Err(SubmitError::SendEmail(String::from("connection closed by server")));
+            }
+            line.push(byte[0]);
+        }
+        let line = String::from_utf8_lossy(&line).trim_right().to_owned();
+        if line.len() < 3 || line[..3].parse::<u32>().ok() != Some(code) {
+            return Err(SubmitError::SendEmail(line));
+        }
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok(());
+        }
+    }
+}
+
+/// The plain address of a `Name <address>` style mailbox.
+pub fn bare_address(addr: &str) -> String {
+    match addr.parse::<Mailbox>() {
+        Ok(mb) => mb.address,
+        Err(_) => addr.trim().to_owned(),
+    }
+}
+
+/// Create a new Message-Id for a message sent by `from`.
+pub fn new_message_id(from: &str, count: usize) -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    format!("{}.{}-{}-git-submit-{}", now.as_secs(), now.subsec_nanos(), count,
+            bare_address(from))
+}
+
+/// Make sure every patch file has a Message-Id header, adding a new one
+/// where it is missing, and return them in order.  Both `send_patches`
+/// and `git send-email` keep existing Message-Ids, so this is known
+/// before anything is sent.
+pub fn assign_message_ids(files: &[PathBuf], from: &str) -> Result<Vec<String>, SubmitError> {
+    let mut message_ids = Vec::new();
+    for (i, file) in files.iter().enumerate() {
+        let mut contents = Vec::new();
+        try!(try!(File::open(file)).read_to_end(&mut contents));
+        let (headers, body) = format::split_mail(&contents);
+        if let Some(message_id) = archive::message_id(headers.as_str()) {
+            message_ids.push(message_id);
+            continue;
+        }
+        let message_id = new_message_id(from, i);
+        let mut new = format!("{}Message-Id: <{}>\n\n", headers, message_id).into_bytes();
+        new.extend_from_slice(body);
+        try!(try!(File::create(file)).write_all(&new));
+        message_ids.push(message_id);
+    }
+    Ok(message_ids)
+}
+
+/// The addresses in the Cc header of a patch file, which may be folded
+/// over several lines.
+pub fn cc_header(contents: &str) -> Result<Vec<String>, SubmitError> {
+    let mut lines = contents.lines().peekable();
+    let mut addresses = Vec::new();
+    while let Some(line) = lines.next() {
+        if line.is_empty() {
+            break;
+        }
+        if line.to_lowercase().starts_with("cc:") {
+            let mut value = line[3..].trim().to_owned();
+            while lines.peek().map_or(false, |l| l.starts_with(' ') || l.starts_with('\t')) {
+                value.push(' ');
+                value.push_str(lines.next().unwrap().trim());
+            }
+            addresses.extend(try!(address::parse(value.as_str())));
+        }
+    }
+    Ok(addresses)
+}
+
+/// Add addresses to the Cc header of a patch file, or start one if
+/// there is none yet.  Addresses that are already in it, or that are
+/// suppressed, are skipped.
+fn add_cc(contents: &str, addresses: &[String], suppressed: &[String])
+          -> Result<String, SubmitError> {
+    let mut known = try!(cc_header(contents)).iter()
+        .chain(suppressed.iter())
+        .map(|a| address::key(a))
+        .collect::<Vec<_>>();
+    let mut new = Vec::new();
+    for addr in addresses.iter().flat_map(|a| address::normalize(a)) {
+        let key = address::key(addr.as_str());
+        if !known.contains(&key) {
+            known.push(key);
+            new.push(addr);
+        }
+    }
+    if new.is_empty() {
+        return Ok(contents.to_owned());
+    }
+
+    let end = contents.find("\n\n").map_or(contents.len(), |pos| pos + 1);
+    let mut headers = contents[..end].lines().map(String::from).collect::<Vec<_>>();
+    let mut last_cc = None;
+    let mut in_cc = false;
+    for (i, line) in headers.iter().enumerate() {
+        let folded = line.starts_with(' ') || line.starts_with('\t');
+        in_cc = line.to_lowercase().starts_with("cc:") || (in_cc && folded);
+        if in_cc {
+            last_cc = Some(i);
+        }
+    }
+    match last_cc {
+        Some(i) => headers[i].push_str(format!(",\n\t{}", new.join(",\n\t")).as_str()),
+        None => headers.push(format!("Cc: {}", new.join(",\n\t"))),
+    }
+    Ok(format!("{}\n{}", headers.join("\n"), &contents[end..]))
+}
+
+/// Remove addresses from the Cc header of a patch file.  The header is
+/// dropped altogether if none are left.
+fn remove_cc(contents: &str, removed: &[String]) -> Result<String, SubmitError> {
+    let removed = removed.iter().map(|a| address::key(a)).collect::<Vec<_>>();
+    let cc = try!(cc_header(contents));
+    let kept = cc.iter()
+        .filter(|a| !removed.contains(&address::key(a)))
+        .cloned()
+        .collect::<Vec<_>>();
+    if kept.len() == cc.len() {
+        return Ok(contents.to_owned());
+    }
+
+    let end = contents.find("\n\n").map_or(contents.len(), |pos| pos + 1);
+    let mut headers = Vec::new();
+    let mut first_cc = None;
+    let mut in_cc = false;
+    for line in contents[..end].lines() {
+        let folded = line.starts_with(' ') || line.starts_with('\t');
+        in_cc = line.to_lowercase().starts_with("cc:") || (in_cc && folded);
+        if in_cc {
+            first_cc = first_cc.or(Some(headers.len()));
+        } else {
+            headers.push(String::from(line));
+        }
+    }
+    if let Some(i) = first_cc {
+        if !kept.is_empty() {
+            headers.insert(i, format!("Cc: {}", kept.join(",\n\t")));
+        }
+    }
+    Ok(format!("{}\n{}", headers.join("\n"), &contents[end..]))
+}
+
+/// Rewrite the headers of the patch file at `file`, whose contents are
+/// `contents`, with `rewrite`.  The body is kept as it is.
+fn rewrite_headers<F>(file: &Path, contents: &[u8], rewrite: F) -> Result<(), SubmitError>
+    where F: FnOnce(&str) -> Result<String, SubmitError>
+{
+    let (headers, body) = format::split_mail(contents);
+    let mut new = try!(rewrite(headers.as_str())).into_bytes();
+    new.push(b'\n');
+    new.extend_from_slice(body);
+    try!(try!(File::create(file)).write_all(&new));
+    Ok(())
+}
+
+/// Run the `submit.ccCmd` for a patch file, through the shell like
+/// send-email does, and return the addresses it prints.
+fn run_cc_cmd(cc_cmd: &str, file: &Path) -> Result<Vec<String>, SubmitError> {
+    let output = try!(Command::new("sh")
+                      .arg("-c")
+                      .arg(format!("{} \"$@\"", cc_cmd))
+                      .arg(cc_cmd)
+                      .arg(file)
+                      .output());
+    if !output.status.success() {
+        return Err(SubmitError::SendEmail(format!("{} failed", cc_cmd)));
+    }
+    Ok(String::from_utf8_lossy(output.stdout.as_slice()).lines()
+       .map(|l| l.trim())
+       .filter(|l| !l.is_empty())
+       .map(String::from)
+       .collect())
+}
+
+/// Cc each patch to the addresses in the trailers of its commit message,
+/// and to the ones `cc_cmd` prints for it, by adding them to the Cc
+/// header of the patch file.  The cover letter is cc'd to all of them.
+/// Like the Message-Ids, this is written to the files, so whichever way
+/// the patches are sent they go to the same people.  Nothing is added for
+/// the `suppressed` addresses.
+pub fn assign_cc(files: &[PathBuf], cover: Option<&PathBuf>, cc_cmd: Option<&str>,
+                 suppressed: &[String]) -> Result<(), SubmitError> {
+    let mut all = Vec::new();
+    for file in files.iter().filter(|f| Some(*f) != cover) {
+        let mut contents = Vec::new();
+        try!(try!(File::open(file)).read_to_end(&mut contents));
+        let mut addresses = trailers::patch_addresses(&String::from_utf8_lossy(&contents));
+        if let Some(cc_cmd) = cc_cmd {
+            addresses.extend(try!(run_cc_cmd(cc_cmd, file)));
+        }
+        try!(rewrite_headers(file, &contents, |h| add_cc(h, &addresses, suppressed)));
+        all.extend(addresses);
+    }
+    if let Some(cover) = cover {
+        let mut contents = Vec::new();
+        try!(try!(File::open(cover)).read_to_end(&mut contents));
+        try!(rewrite_headers(cover, &contents, |h| add_cc(h, &all, suppressed)));
+    }
+    Ok(())
+}
+
+/// Take the `removed` addresses out of the Cc headers of the patch
+/// files again, when the user decided they shouldn't get the series
+/// after all.
+pub fn strip_cc(files: &[PathBuf], removed: &[String]) -> Result<(), SubmitError> {
+    if removed.is_empty() {
+        return Ok(());
+    }
+    for file in files {
+        let mut contents = Vec::new();
+        try!(try!(File::open(file)).read_to_end(&mut contents));
+        try!(rewrite_headers(file, &contents, |h| remove_cc(h, removed)));
+    }
+    Ok(())
+}
+
+/// Turn the patch files into messages with the recipients and threading
+/// headers `git send-email` would add, and deliver them through
+/// `transport`.  The first message replies to `in_reply_to`, all others
+/// to the first one, and `references` are the messages of the thread
+/// `in_reply_to` is in.  Each message is also sent to the addresses in
+/// the Cc header of its patch file.
+pub fn send_patches(transport: &mut Transport, files: &[PathBuf], from: &str, to: &[String],
+                    cc: &[String], in_reply_to: Option<String>, references: &[String])
+                    -> Result<(), SubmitError> {
+    if to.is_empty() && cc.is_empty() {
+        return Err(SubmitError::Usage(String::from("please specify at least one address")));
+    }
+    let mut first_id: Option<String> = None;
+    for (i, file) in files.iter().enumerate() {
+        let (message, message_id, recipients) =
+            try!(prepare_message(file, from, to, cc, first_id.as_ref().or(in_reply_to.as_ref()),
+                                 in_reply_to.as_ref(), references, i));
+        try!(transport.send(from, &recipients, &message));
+        println!("Sent {}", message_id);
+        if first_id.is_none() {
+            first_id = Some(message_id);
+        }
+    }
+    Ok(())
+}
+
+fn prepare_message(file: &Path, from: &str, to: &[String], cc: &[String],
+                   parent: Option<&String>, root: Option<&String>, references: &[String],
+                   count: usize)
+                   -> Result<(Vec<u8>, String, Vec<String>), SubmitError> {
+    let mut contents = Vec::new();
+    try!(try!(File::open(file)).read_to_end(&mut contents));
+    let (file_headers, body) = format::split_mail(&contents);
+    let mut cc = cc.to_vec();
+    for addr in try!(cc_header(file_headers.as_str())) {
+        let key = address::key(addr.as_str());
+        if !to.iter().chain(cc.iter()).any(|a| address::key(a) == key) {
+            cc.push(addr);
+        }
+    }
+    let mut fields = format::header_fields(file_headers.as_str());
+    if fields.first().map_or(false, |f| f.starts_with("From ")) {
+        fields.remove(0);
+    }
+
+    let mut headers = Vec::new();
+    let mut author = None;
+    let mut message_id = None;
+    for field in fields {
+        let lower = field.to_lowercase();
+        // The Cc header of the file is merged into the one added below.
+        if lower.starts_with("cc:") {
+            continue;
+        }
+        if lower.starts_with("from:") {
+            author = Some(format::unfold(&field[5..]));
+            continue;
+        }
+        if lower.starts_with("message-id:") {
+            message_id = Some(format::unfold(&field[11..])
+                              .trim_matches(|c: char| c == '<' || c == '>').to_owned());
+        }
+        headers.push(field);
+    }
+    let message_id = message_id.unwrap_or_else(|| {
+        let id = new_message_id(from, count);
+        headers.push(format!("Message-Id: <{}>", id));
+        id
+    });
+
+    let mut message = format!("From: {}\n", from);
+    for header in headers {
+        message.push_str(header.as_str());
+        message.push('\n');
+    }
+    if !to.is_empty() {
+        message.push_str(format!("To: {}\n", to.join(",\n\t")).as_str());
+    }
+    if !cc.is_empty() {
+        message.push_str(format!("Cc: {}\n", cc.join(",\n\t")).as_str());
+    }
+    if let Some(parent) = parent {
+        message.push_str(format!("In-Reply-To: <{}>\n", parent).as_str());
+        let mut thread = references.to_vec();
+        thread.extend(root.cloned());
+        if root != Some(parent) {
+            thread.push(parent.clone());
+        }
+        let thread = thread.iter().map(|id| format!("<{}>", id)).collect::<Vec<_>>();
+        message.push_str(format!("References: {}\n", thread.join("\n\t")).as_str());
+    }
+    message.push('\n');
+    // Like send-email, keep the author intact when sending someone
+    // else's patch.
+    if let Some(author) = author {
+        if bare_address(author.as_str()) != bare_address(from) {
+            message.push_str(format!("From: {}\n\n", author).as_str());
+        }
+    }
+    let mut message = message.into_bytes();
+    message.extend_from_slice(body);
+    let mut recipients = to.to_vec();
+    recipients.extend(cc);
+    Ok((message, message_id, recipients))
+}
+
+/// The address mails are sent from, `sendemail.from` or the configured
+/// committer identity.
+pub fn sender(config: &Config) -> Result<String, SubmitError> {
+    if let Ok(from) = config.get_string("sendemail.from") {
+        return Ok(from);
+    }
+    let name = try!(config.get_string("user.name"));
+    let email = try!(config.get_string("user.email"));
+    Ok(address::format(Mailbox::new_with_name(name, email)))
+}
+
+/// Choose the transport from `submit.transport`.  `None` means patches
+/// are handed to `git send-email`, which is also the default.  The
+/// `mbox` and `maildir` transports write to `submit.transportPath`.
+pub fn from_config(config: &Config) -> Result<Option<Box<Transport>>, SubmitError> {
+    let path = config.get_path("submit.transportPath");
+    match config.get_string("submit.transport") {
+        Err(_) => Ok(None),
+        Ok(ref transport) if transport == "send-email" => Ok(None),
+        Ok(ref transport) if transport == "smtp" =>
+            Ok(Some(Box::new(try!(Smtp::from_config(config))))),
+        Ok(ref transport) if transport == "sendmail" => {
+            let command = config.get_string("sendemail.sendmailCmd")
+                .unwrap_or(String::from("sendmail"));
+            Ok(Some(Box::new(Sendmail::new(command.as_str()))))
+        },
+        Ok(ref transport) if transport == "mbox" => Ok(Some(Box::new(Mbox::new(&try!(path))))),
+        Ok(ref transport) if transport == "maildir" =>
+            Ok(Some(Box::new(Maildir::new(&try!(path))))),
+        Ok(transport) => Err(SubmitError::Usage(format!("unknown transport '{}'", transport))),
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::{Maildir, Mbox, Sendmail, Smtp, Transport, add_cc, assign_cc, assign_message_ids,
+                bare_address, cc_header, from_config, prepare_message, remove_cc, send_patches,
+                sender, strip_cc};
+
+    use archive::{self, Archive};
+    use git2::Repository;
+    use std::fs::{self, File};
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use std::sync::mpsc::{Receiver, channel};
+    use std::thread;
+    use tempdir::TempDir;
+
+    pub const PATCH: &'static str = "\
+From 0123456789abcdef0123456789abcdef01234567 Mon Sep 17 00:00:00 2001
+From: A U Thor <author@example.net>
+Date: Mon, 18 Jan 2016 17:57:18 +0100
+Subject: [PATCH] add a file
+
+The message.
+.dotted
+---
+ 1 | 1 +
+ 1 file changed, 1 insertion(+)
+
+diff --git a/1 b/1
+new file mode 100644
+--- /dev/null
++++ b/1
+@@ -0,0 +1 @@
++Hello it's me!
+";
+
+    /// A minimal SMTP server that accepts everything, and hands back the
+    /// transcript of each session.
+    pub fn smtp_sink() -> (u16, Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut writer = stream.unwrap();
+                let mut reader = BufReader::new(writer.try_clone().unwrap());
+                writer.write_all(b"220 localhost ESMTP\r\n").unwrap();
+                let mut transcript = String::new();
+                let mut in_data = false;
+                loop {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).unwrap() == 0 {
+                        break;
+                    }
+                    transcript.push_str(line.as_str());
+                    if in_data {
+                        if line == ".\r\n" {
+                            in_data = false;
+                            writer.write_all(b"250 OK\r\n").unwrap();
+                        }
+                        continue;
+                    }
+                    let reply = if line.starts_with("EHLO") {
+                        &b"250-localhost\r\n250 AUTH PLAIN\r\n"[..]
+                    } else if line.starts_with("AUTH") {
+                        &b"235 OK\r\n"[..]
+                    } else if line.starts_with("DATA") {
+                        in_data = true;
+                        &b"354 go ahead\r\n"[..]
+                    } else if line.starts_with("QUIT") {
+                        &b"221 bye\r\n"[..]
+                    } else {
+                        &b"250 OK\r\n"[..]
+                    };
+                    writer.write_all(reply).unwrap();
+                    if line.starts_with("QUIT") {
+                        break;
+                    }
+                }
+                tx.send(transcript).unwrap();
+            }
+        });
+        (port, rx)
+    }
+
+    pub fn write_patches(dir: &TempDir, count: usize) -> Vec<PathBuf> {
+        let mut files = Vec::new();
+        for i in 0..count {
+            let path = dir.path().join(format!("{:04}-add-a-file.patch", i + 1));
+            let mut file = File::create(&path).unwrap();
+            file.write_all(PATCH.as_bytes()).unwrap();
+            files.push(path);
+        }
+        files
+    }
+
+    #[test]
+    fn test_sender() {
+        let tempdir = TempDir::new("git-submit").unwrap();
+        let repo = Repository::init(tempdir.path()).unwrap();
+        let mut config = repo.config().unwrap();
+        config.set_str("user.name", "King, Jeff").unwrap();
+        config.set_str("user.email", "peff@peff.net").unwrap();
+        assert_eq!(sender(&config).unwrap(), "\"King, Jeff\" <peff@peff.net>");
+        config.set_str("sendemail.from", "Jeff King <peff@peff.net>").unwrap();
+        assert_eq!(sender(&config).unwrap(), "Jeff King <peff@peff.net>");
+    }
+
+    #[test]
+    fn test_bare_address() {
+        assert_eq!(bare_address("A U Thor <author@example.net>"), "author@example.net");
+        assert_eq!(bare_address("author@example.net"), "author@example.net");
+    }
+
+    #[test]
+    fn test_prepare_message() {
+        let tempdir = TempDir::new("git-submit").unwrap();
+        let files = write_patches(&tempdir, 1);
+        let root = String::from("root@example.com");
+        let parent = String::from("parent@example.com");
+        let (message, message_id, recipients) =
+            prepare_message(&files[0], "Me <me@example.com>",
+                            &[String::from("list@example.com")], &[], Some(&parent),
+                            Some(&root), &[String::from("thread@example.com")], 1).unwrap();
+        let message = String::from_utf8(message).unwrap();
+        assert!(message.starts_with("From: Me <me@example.com>\n\
+                                     Date: Mon, 18 Jan 2016 17:57:18 +0100\n\
+                                     Subject: [PATCH] add a file\n"));
+        assert!(message.contains(format!("Message-Id: <{}>\n", message_id).as_str()));
+        assert!(message_id.ends_with("-1-git-submit-me@example.com"));
+        assert!(message.contains("To: list@example.com\n"));
+        assert!(!message.contains("Cc:"));
+        assert_eq!(recipients, vec!(String::from("list@example.com")));
+        assert!(message.contains("In-Reply-To: <parent@example.com>\n\
+                                  References: <thread@example.com>\n\t<root@example.com>\n\
+                                  \t<parent@example.com>\n\n\
+                                  From: A U Thor <author@example.net>\n\nThe message.\n"));
+    }
+
+    #[test]
+    fn test_prepare_message_latin1() {
+        let tempdir = TempDir::new("git-submit").unwrap();
+        let files = write_patches(&tempdir, 1);
+        let mut contents = PATCH.replace("+Hello it's me!\n", "").into_bytes();
+        contents.extend_from_slice(b"+caf\xe9\n");
+        File::create(&files[0]).unwrap().write_all(&contents).unwrap();
+        assign_message_ids(&files, "Me <me@example.com>").unwrap();
+        assign_cc(&files, None, None, &[]).unwrap();
+        let (message, _, _) = prepare_message(&files[0], "Me <me@example.com>",
+                                              &[String::from("list@example.com")], &[], None,
+                                              None, &[], 0).unwrap();
+        assert!(message.ends_with(b"@@ -0,0 +1 @@\n+caf\xe9\n"));
+    }
+
+    #[test]
+    fn test_prepare_message_folded_author() {
+        let tempdir = TempDir::new("git-submit").unwrap();
+        let files = write_patches(&tempdir, 1);
+        let author = "From: =?UTF-8?q?J=C3=B6rg_=C3=84rger_with_a_name_that_is_long_eno?=\n \
+                      =?UTF-8?q?ugh_to_be_folded?= <author@example.net>";
+        File::create(&files[0]).unwrap()
+            .write_all(PATCH.replace("From: A U Thor <author@example.net>", author).as_bytes())
+            .unwrap();
+        let (message, _, _) = prepare_message(&files[0], "Me <me@example.com>",
+                                              &[String::from("list@example.com")], &[], None,
+                                              None, &[], 0).unwrap();
+        let message = String::from_utf8(message).unwrap();
+        assert!(message.starts_with("From: Me <me@example.com>\n\
+                                     Date: Mon, 18 Jan 2016 17:57:18 +0100\n"));
+        assert!(message.contains("\n\nFrom: =?UTF-8?q?J=C3=B6rg_=C3=84rger_with_a_name_that_is_\
+                                  long_eno?= =?UTF-8?q?ugh_to_be_folded?= <author@example.net>\n\n\
+                                  The message.\n"));
+    }
+
+    #[test]
+    fn test_assign_message_ids() {
+        let tempdir = TempDir::new("git-submit").unwrap();
+        let files = write_patches(&tempdir, 2);
+        let message_ids = assign_message_ids(&files, "Me <me@example.com>").unwrap();
+        assert_eq!(message_ids.len(), 2);
+        assert!(message_ids[0] != message_ids[1]);
+        assert_eq!(assign_message_ids(&files, "Me <me@example.com>").unwrap(), message_ids);
+
+        let mut contents = String::new();
+        File::open(&files[0]).unwrap().read_to_string(&mut contents).unwrap();
+        assert!(contents.contains(format!("Subject: [PATCH] add a file\nMessage-Id: <{}>\n\n\
+                                           The message.\n", message_ids[0]).as_str()));
+        let (_, message_id, _) = prepare_message(&files[0], "Me <me@example.com>", &[], &[], None,
+                                                 None, &[], 0).unwrap();
+        assert_eq!(message_id, message_ids[0]);
+    }
+
+    #[test]
+    fn test_add_cc() {
+        let patch = "From 1234 Mon Sep 17 00:00:00 2001\nSubject: [PATCH] add a file\n\n\
+                     Cc: body@example.com\n";
+        let added = add_cc(patch, &[String::from("A U Thor <author@example.net>"),
+                                    String::from("Author@Example.net")], &[]).unwrap();
+        assert_eq!(added, "From 1234 Mon Sep 17 00:00:00 2001\nSubject: [PATCH] add a file\n\
+                           Cc: A U Thor <author@example.net>\n\nCc: body@example.com\n");
+        let added = add_cc(added.as_str(), &[String::from("author@example.net"),
+                                             String::from("list@example.com, Me <me@example.com>"),
+                                             String::from("Jeff King <peff@peff.net>")],
+                           &[String::from("ME@example.com")]).unwrap();
+        assert_eq!(added, "From 1234 Mon Sep 17 00:00:00 2001\nSubject: [PATCH] add a file\n\
+                           Cc: A U Thor <author@example.net>,\n\tlist@example.com,\n\
+                           \tJeff King <peff@peff.net>\n\nCc: body@example.com\n");
+        assert_eq!(cc_header(added.as_str()).unwrap(),
+                   vec!(String::from("A U Thor <author@example.net>"),
+                        String::from("list@example.com"),
+                        String::from("Jeff King <peff@peff.net>")));
+        assert_eq!(add_cc(added.as_str(), &[String::from("peff@peff.net")], &[]).unwrap(), added);
+        assert!(add_cc("Cc: @@@\n\nbody\n", &[String::from("peff@peff.net")], &[]).is_err());
+    }
+
+    #[test]
+    fn test_assign_cc() {
+        let tempdir = TempDir::new("git-submit").unwrap();
+        let mut files = write_patches(&tempdir, 2);
+        let cover = tempdir.path().join("0000-cover-letter.patch");
+        File::create(&cover).unwrap()
+            .write_all(b"Subject: [PATCH 0/2] *** SUBJECT HERE ***\n\n*** BLURB HERE ***\n")
+            .unwrap();
+        files.insert(0, cover.clone());
+        let mut file = File::create(&files[2]).unwrap();
+        file.write_all(PATCH.replace("The message.\n",
+                                     "The message.\n\nReviewed-by: Jeff King <peff@peff.net>\n\
+                                      Signed-off-by: A U Thor <author@example.net>\n")
+                       .as_bytes()).unwrap();
+        let script = tempdir.path().join("cc-cmd.sh");
+        File::create(&script).unwrap()
+            .write_all(b"case \"$1\" in *0001-*) echo maintainer@example.com;; esac\n").unwrap();
+
+        assign_cc(&files, Some(&cover), Some(format!("sh {}", script.display()).as_str()),
+                  &[String::from("author@example.net")]).unwrap();
+        let headers = files.iter().map(|f| {
+            let mut contents = String::new();
+            File::open(f).unwrap().read_to_string(&mut contents).unwrap();
+            cc_header(contents.as_str()).unwrap()
+        }).collect::<Vec<_>>();
+        assert_eq!(headers[0], vec!(String::from("maintainer@example.com"),
+                                    String::from("Jeff King <peff@peff.net>")));
+        assert_eq!(headers[1], vec!(String::from("maintainer@example.com")));
+        assert_eq!(headers[2], vec!(String::from("Jeff King <peff@peff.net>")));
+
+        let (message, _, recipients) =
+            prepare_message(&files[2], "A U Thor <author@example.net>",
+                            &[String::from("list@example.com")],
+                            &[String::from("peff@peff.net")], None, None, &[], 0).unwrap();
+        let message = String::from_utf8(message).unwrap();
+        assert!(message.contains("To: list@example.com\nCc: peff@peff.net\n\n"));
+        assert_eq!(recipients, vec!(String::from("list@example.com"),
+                                    String::from("peff@peff.net")));
+        let (message, _, recipients) =
+            prepare_message(&files[0], "A U Thor <author@example.net>",
+                            &[String::from("list@example.com")], &[], None, None, &[], 0)
+                .unwrap();
+        let message = String::from_utf8(message).unwrap();
+        assert!(message.contains("To: list@example.com\nCc: maintainer@example.com,\n\t\
+                                  Jeff King <peff@peff.net>\n"));
+        assert_eq!(recipients.len(), 3);
+
+        assert!(assign_cc(&files, Some(&cover), Some("false"), &[]).is_err());
+
+        strip_cc(&files, &[String::from("MAINTAINER@example.com")]).unwrap();
+        let mut contents = String::new();
+        File::open(&files[0]).unwrap().read_to_string(&mut contents).unwrap();
+        assert!(contents.starts_with("Subject: [PATCH 0/2] *** SUBJECT HERE ***\n\
+                                      Cc: Jeff King <peff@peff.net>\n\n"));
+        let mut contents = String::new();
+        File::open(&files[1]).unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(cc_header(contents.as_str()).unwrap(), Vec::<String>::new());
+        assert!(!contents.contains("maintainer@example.com"));
+    }
+
+    #[test]
+    fn test_remove_cc() {
+        let patch = "From 1234 Mon Sep 17 00:00:00 2001\nCc: A U Thor <author@example.net>,\n\
+                     \tlist@example.com\nSubject: [PATCH] add a file\nCc: peff@peff.net\n\n\
+                     Cc: list@example.com\n";
+        assert_eq!(remove_cc(patch, &[String::from("LIST@example.com")]).unwrap(),
+                   "From 1234 Mon Sep 17 00:00:00 2001\n\
+                    Cc: A U Thor <author@example.net>,\n\tpeff@peff.net\n\
+                    Subject: [PATCH] add a file\n\nCc: list@example.com\n");
+        assert_eq!(remove_cc(patch, &[String::from("author@example.net"),
+                                      String::from("list@example.com"),
+                                      String::from("Jeff King <peff@peff.net>")]).unwrap(),
+                   "From 1234 Mon Sep 17 00:00:00 2001\nSubject: [PATCH] add a file\n\n\
+                    Cc: list@example.com\n");
+        assert_eq!(remove_cc(patch, &[String::from("me@example.com")]).unwrap(), patch);
+    }
+
+    #[test]
+    fn test_send_patches_smtp() {
+        let tempdir = TempDir::new("git-submit").unwrap();
+        let repo = Repository::init(tempdir.path()).unwrap();
+        let (port, transcripts) = smtp_sink();
+        let mut config = repo.config().unwrap();
+        config.set_str("submit.transport", "smtp").unwrap();
+        config.set_str("sendemail.smtpServer", "127.0.0.1").unwrap();
+        config.set_i32("sendemail.smtpServerPort", port as i32).unwrap();
+        config.set_str("sendemail.smtpUser", "me").unwrap();
+        config.set_str("sendemail.smtpPass", "secret").unwrap();
+
+        let mut transport = from_config(&config).unwrap().unwrap();
+        let files = write_patches(&tempdir, 2);
+        send_patches(&mut *transport, &files, "A U Thor <author@example.net>",
+                     &[String::from("list@example.com")], &[String::from("cc@example.com")],
+                     Some(String::from("cover@example.com")),
+                     &[String::from("thread@example.com")]).unwrap();
+
+        let first = transcripts.recv().unwrap();
+        assert!(first.starts_with("EHLO localhost\r\nAUTH PLAIN AG1lAHNlY3JldA==\r\n\
+                                   MAIL FROM:<author@example.net>\r\n\
+                                   RCPT TO:<list@example.com>\r\n\
+                                   RCPT TO:<cc@example.com>\r\nDATA\r\n"));
+        assert!(first.contains("In-Reply-To: <cover@example.com>\r\n\
+                                References: <thread@example.com>\r\n\t<cover@example.com>\r\n"));
+        assert!(first.contains("\r\n..dotted\r\n"));
+        assert!(first.ends_with("\r\n.\r\nQUIT\r\n"));
+        let second = transcripts.recv().unwrap();
+        assert!(second.contains("References: <thread@example.com>\r\n\t<cover@example.com>\r\n"));
+        assert!(second.contains("-0-git-submit-author@example.net>\r\n"));
+    }
+
+    #[test]
+    fn test_sendmail_transport() {
+        let tempdir = TempDir::new("git-submit").unwrap();
+        let script = tempdir.path().join("sendmail.sh");
+        let mut file = File::create(&script).unwrap();
+        write!(file, "echo \"$@\" > {0}/args\ncat > {0}/message\n",
+               tempdir.path().display()).unwrap();
+
+        let mut transport = Sendmail::new(format!("sh {}", script.display()).as_str());
+        transport.send("A U Thor <author@example.net>",
+                       &[String::from("List <list@example.com>")], b"Subject: test\n\nbody\n")
+            .unwrap();
+
+        let mut args = String::new();
+        File::open(tempdir.path().join("args")).unwrap().read_to_string(&mut args).unwrap();
+        assert_eq!(args, "-i -fauthor@example.net list@example.com\n");
+        let mut message = String::new();
+        File::open(tempdir.path().join("message")).unwrap()
+            .read_to_string(&mut message).unwrap();
+        assert_eq!(message, "Subject: test\n\nbody\n");
+
+        let mut failing = Sendmail::new("false");
+        assert!(failing.send("author@example.net", &[], b"").is_err());
+    }
+
+    #[test]
+    fn test_mbox_transport() {
+        let tempdir = TempDir::new("git-submit").unwrap();
+        let path = tempdir.path().join("sent.mbox");
+        let files = write_patches(&tempdir, 2);
+        let mut transport = Mbox::new(&path);
+        send_patches(&mut transport, &files, "A U Thor <author@example.net>",
+                     &[String::from("list@example.com")], &[], None, &[]).unwrap();
+
+        let mut contents = String::new();
+        File::open(&path).unwrap().read_to_string(&mut contents).unwrap();
+        let messages = archive::split_mbox(contents.as_str());
+        assert_eq!(messages.len(), 2);
+        let first_id = archive::message_id(messages[0].as_str()).unwrap();
+        assert!(messages[1].contains(format!("In-Reply-To: <{}>", first_id).as_str()));
+        assert!(archive::Mbox::new(&path).fetch(first_id.as_str()).is_ok());
+    }
+
+    #[test]
+    fn test_maildir_transport() {
+        let tempdir = TempDir::new("git-submit").unwrap();
+        let path = tempdir.path().join("Maildir");
+        let files = write_patches(&tempdir, 2);
+        let mut transport = Maildir::new(&path);
+        send_patches(&mut transport, &files, "A U Thor <author@example.net>",
+                     &[String::from("list@example.com")], &[], None, &[]).unwrap();
+
+        assert_eq!(fs::read_dir(path.join("new")).unwrap().count(), 2);
+        assert_eq!(fs::read_dir(path.join("tmp")).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_transport_from_config() {
+        let tempdir = TempDir::new("git-submit").unwrap();
+        let repo = Repository::init(tempdir.path()).unwrap();
+        let mut config = repo.config().unwrap();
+        assert!(from_config(&config).unwrap().is_none());
+        config.set_str("submit.transport", "smtp").unwrap();
+        assert!(from_config(&config).unwrap().is_some());
+        assert!(Smtp::from_config(&config).is_ok());
+        config.set_str("submit.transport", "sendmail").unwrap();
+        assert!(from_config(&config).unwrap().is_some());
+        config.set_str("submit.transport", "mbox").unwrap();
+        assert!(from_config(&config).is_err());
+        config.set_str("submit.transportPath", "sent.mbox").unwrap();
+        assert!(from_config(&config).unwrap().is_some());
+        config.set_str("submit.transport", "pigeon").unwrap();
+        assert!(from_config(&config).is_err());
+    }
+
+    #[test]
+    fn test_smtp_from_config() {
+        let tempdir = TempDir::new("git-submit").unwrap();
+        let repo = Repository::init(tempdir.path()).unwrap();
+        let mut config = repo.config().unwrap();
+        config.set_i32("sendemail.smtpServerPort", 70000).unwrap();
+        assert!(Smtp::from_config(&config).is_err());
+        config.set_i32("sendemail.smtpServerPort", 0).unwrap();
+        assert!(Smtp::from_config(&config).is_err());
+        config.set_i32("sendemail.smtpServerPort", 465).unwrap();
+        config.set_str("sendemail.smtpSslCertPath",
+                       tempdir.path().join("missing.pem").to_str().unwrap()).unwrap();
+        let smtp = Smtp::from_config(&config).unwrap();
+        assert_eq!(smtp.port, 465);
+        assert!(smtp.tls_context().is_err());
+    }
+}
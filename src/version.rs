@@ -0,0 +1,184 @@
+use format;
+use git2::{Error, Oid, Repository};
+
+/// What was sent for one version of a series.  It is stored as the
+/// message of the annotated `<branch>-vN` tag, so later runs can find
+/// out what happened to the previous versions.
+#[derive(Debug, PartialEq)]
+pub struct VersionInfo {
+    pub base: Option<Oid>,
+    pub in_reply_to: Option<String>,
+    pub to: Vec<String>,
+    pub cc: Vec<String>,
+    pub message_ids: Vec<String>,
+    pub sent: u64,
+    pub cover_subject: Option<String>,
+    pub cover_blurb: Option<String>,
+}
+
+impl VersionInfo {
+    /// The tag message, a subject line followed by one `Key: value`
+    /// line for every recorded value, and the blurb of the cover letter
+    /// as the last paragraph.
+    pub fn message(&self, branch_name: &str, version: u32) -> String {
+        let mut message = format!("{} v{}\n\n", branch_name, version);
+        if let Some(base) = self.base {
+            message.push_str(format!("Base: {}\n", base).as_str());
+        }
+        if let Some(ref in_reply_to) = self.in_reply_to {
+            message.push_str(format!("In-Reply-To: {}\n", in_reply_to).as_str());
+        }
+        for addr in self.to.iter() {
+            message.push_str(format!("To: {}\n", addr).as_str());
+        }
+        for addr in self.cc.iter() {
+            message.push_str(format!("Cc: {}\n", addr).as_str());
+        }
+        for message_id in self.message_ids.iter() {
+            message.push_str(format!("Message-Id: {}\n", message_id).as_str());
+        }
+        message.push_str(format!("Sent: {}\n", self.sent).as_str());
+        if let Some(ref subject) = self.cover_subject {
+            message.push_str(format!("Cover-Subject: {}\n", subject).as_str());
+        }
+        if let Some(ref blurb) = self.cover_blurb {
+            message.push_str(format!("\n{}\n", blurb).as_str());
+        }
+        message
+    }
+
+    pub fn parse(message: &str) -> VersionInfo {
+        let mut info = VersionInfo {
+            base: None,
+            in_reply_to: None,
+            to: Vec::new(),
+            cc: Vec::new(),
+            message_ids: Vec::new(),
+            sent: 0,
+            cover_subject: None,
+            cover_blurb: None,
+        };
+        let mut lines = message.lines().skip_while(|l| !l.is_empty()).skip(1);
+        for line in lines.by_ref() {
+            if line.is_empty() {
+                break;
+            }
+            let mut split = line.splitn(2, ": ");
+            let key = split.next().unwrap_or("");
+            let value = split.next().unwrap_or("").to_owned();
+            match key {
+                "Base" => info.base = Oid::from_str(value.as_str()).ok(),
+                "In-Reply-To" => info.in_reply_to = Some(value),
+                "To" => info.to.push(value),
+                "Cc" => info.cc.push(value),
+                "Message-Id" => info.message_ids.push(value),
+                "Sent" => info.sent = value.parse::<u64>().unwrap_or(0),
+                "Cover-Subject" => info.cover_subject = Some(value),
+                _ => (),
+            }
+        }
+        let blurb = lines.collect::<Vec<_>>().join("\n");
+        if !blurb.trim().is_empty() {
+            info.cover_blurb = Some(blurb.trim().to_owned());
+        }
+        info
+    }
+
+    /// Read the information recorded for a version.  Versions tagged
+    /// before the tags were annotated have none.
+    pub fn read(repo: &Repository, branch_name: &str, version: u32)
+                -> Result<Option<VersionInfo>, Error> {
+        let reference = try!(repo.find_reference(tag_ref(branch_name, version).as_str()));
+        let tag = match reference.target() {
+            Some(oid) => match repo.find_tag(oid) {
+                Ok(tag) => tag,
+                Err(_) => return Ok(None),
+            },
+            None => return Ok(None),
+        };
+        Ok(tag.message().map(VersionInfo::parse))
+    }
+}
+
+/// Format seconds since the epoch as a UTC date, e.g. for the `Sent`
+/// time of a version.
+pub fn format_time(secs: u64) -> String {
+    let (year, month, day) = format::civil((secs / 86400) as i64);
+    format!("{}-{:02}-{:02} {:02}:{:02} UTC", year, month, day, secs % 86400 / 3600,
+            secs % 3600 / 60)
+}
+
+pub fn tag_name(branch_name: &str, version: u32) -> String {
+    format!("{}-v{}", branch_name, version)
+}
+
+fn tag_ref(branch_name: &str, version: u32) -> String {
+    format!("refs/tags/{}", tag_name(branch_name, version))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{VersionInfo, format_time};
+
+    use git2::{Oid, Repository, Signature};
+    use std::fs::File;
+    use std::io::Write;
+    use std::path::Path;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_version_info_roundtrip() {
+        let info = VersionInfo {
+            base: Some(Oid::from_str("0123456789abcdef0123456789abcdef01234567").unwrap()),
+            in_reply_to: Some(String::from("cover-v1@example.com")),
+            to: vec!(String::from("List <list@example.com>")),
+            cc: vec!(String::from("a@example.com"), String::from("b@example.com")),
+            message_ids: vec!(String::from("1@example.com"), String::from("2@example.com")),
+            sent: 1453136238,
+            cover_subject: Some(String::from("add a file")),
+            cover_blurb: Some(String::from("The file is needed.\n\nChanges since v1:\n\n\
+                                            - To: a typo")),
+        };
+        let message = info.message("topic", 2);
+        assert!(message.starts_with("topic v2\n\nBase: 0123456789abcdef"));
+        assert_eq!(VersionInfo::parse(message.as_str()), info);
+    }
+
+    #[test]
+    fn test_format_time() {
+        assert_eq!(format_time(0), "1970-01-01 00:00 UTC");
+        assert_eq!(format_time(1453136238), "2016-01-18 16:57 UTC");
+        assert_eq!(format_time(1456704000), "2016-02-29 00:00 UTC");
+    }
+
+    #[test]
+    fn test_version_info_read() {
+        let tempdir = TempDir::new("git-submit").unwrap();
+        let repo = Repository::init(tempdir.path()).unwrap();
+        let mut file = File::create(tempdir.path().join("file")).unwrap();
+        file.write_all(b"content").unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("file")).unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let sig = Signature::now("A U Thor", "author@example.net").unwrap();
+        let oid = repo.commit(Some("HEAD"), &sig, &sig, "commit", &tree, &[]).unwrap();
+        let commit = repo.find_object(oid, None).unwrap();
+
+        repo.tag_lightweight("topic-v1", &commit, false).unwrap();
+        assert_eq!(VersionInfo::read(&repo, "topic", 1).unwrap(), None);
+        assert!(VersionInfo::read(&repo, "topic", 3).is_err());
+
+        let info = VersionInfo {
+            base: None,
+            in_reply_to: None,
+            to: vec!(String::from("list@example.com")),
+            cc: Vec::new(),
+            message_ids: vec!(String::from("1@example.com")),
+            sent: 1453136238,
+            cover_subject: None,
+            cover_blurb: None,
+        };
+        repo.tag("topic-v2", &commit, &sig, info.message("topic", 2).as_str(), false).unwrap();
+        assert_eq!(VersionInfo::read(&repo, "topic", 2).unwrap(), Some(info));
+    }
+}